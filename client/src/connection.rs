@@ -0,0 +1,404 @@
+use {
+    common::clapi::{
+        ClientId, ClientMsg, ClientToServer, Protocol, RequestId, RoomName, ServerMsg, ServerToClient, SessionToken,
+    },
+    futures::{
+        channel::mpsc,
+        future::{self, Either},
+        stream::StreamExt,
+    },
+    log::{info, warn},
+    std::{
+        collections::{BTreeMap, BTreeSet, VecDeque},
+        time::Duration,
+    },
+    wasm_bindgen::JsValue,
+    wasm_bindgen_futures::JsFuture,
+};
+
+use crate::websockets::{self, WsMsg};
+
+const BACKOFF_BASE: Duration = Duration::from_millis(500);
+const BACKOFF_MAX: Duration = Duration::from_secs(30);
+// After this many failed attempts in a row, we stop trying and leave it to the user to reload:
+const MAX_ATTEMPTS: u32 = 20;
+// How long we have to have been welcomed for before a connection counts as having worked, so that
+// the backoff starts again from scratch when it drops. Anything shorter counts as a failed attempt,
+// or a server that lets us in and then drops us straight away would have us back in no time, and
+// forever:
+const STABLE_AFTER: Duration = Duration::from_secs(30);
+// What the server closes the connection with when it's kicked us or had enough of us, in which
+// case coming straight back would only be asking for more of the same:
+const POLICY_VIOLATION: u16 = 1008;
+// So that a long outage can't pile up unbounded work for when we get back:
+const MAX_QUEUED: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnState {
+    Connecting,
+    Open,
+    // Counts from 1:
+    Reconnecting { attempt: u32 },
+    Closed,
+}
+
+pub enum ConnEvent {
+    State(ConnState),
+    Msg(ServerMsg),
+}
+
+// Keeps a connection to the server going, reconnecting with backoff when it drops. Commands sent
+// while we're disconnected are queued up and sent once we're back. If the server still remembers
+// our session we only catch it up on the rooms, name and metadata it hasn't confirmed, otherwise
+// we first restore all of them.
+pub async fn manage(
+    url: String,
+    offered: Vec<Protocol>,
+    mut cmd_rx: mpsc::Receiver<ClientToServer>,
+    mut events: impl FnMut(ConnEvent),
+) {
+    let mut session = Session::default();
+    // The part of the session that the server has confirmed. Sending something only means the
+    // browser's buffered it, which is no use if the connection then drops:
+    let mut synced = Session::default();
+    let mut client_id: Option<ClientId> = None;
+    let mut queue = VecDeque::new();
+    let mut next_id: RequestId = 0;
+    let mut token: Option<SessionToken> = None;
+    let mut attempt = 0;
+    events(ConnEvent::State(ConnState::Connecting));
+    loop {
+        let session_url = token.as_ref().map_or_else(|| url.clone(), |t| with_session(&url, t));
+        let (ws, protocol, mut msg_rx) = match websockets::go(&session_url, &offered).await {
+            Ok(connection) => connection,
+            Err(e) => {
+                attempt += 1;
+                warn!("Connection attempt {} failed: {}", attempt, e);
+                if attempt >= MAX_ATTEMPTS || !wait(attempt, &mut cmd_rx, &mut session, &mut queue, &mut events).await {
+                    return events(ConnEvent::State(ConnState::Closed));
+                }
+                continue
+            }
+        };
+        info!("Connected using {}", protocol);
+        events(ConnEvent::State(ConnState::Open));
+        // Until the server's welcomed us, we don't know whether we'll need to restore our session:
+        let mut welcomed = false;
+        let mut welcomed_at = None;
+        let mut close_code = None;
+
+        loop {
+            match future::select(cmd_rx.next(), msg_rx.next()).await {
+                Either::Left((None, _)) => {
+                    let _ = ws.close();
+                    return events(ConnEvent::State(ConnState::Closed));
+                },
+                Either::Left((Some(body), _)) if !welcomed => {
+                    if !session.record(&body) { enqueue(&mut queue, body) }
+                },
+                Either::Left((Some(body), _)) => {
+                    let restorable = session.record(&body);
+                    let msg = ClientMsg { id: next_id, body };
+                    next_id += 1;
+                    if let Err(e) = websockets::send(&ws, protocol.encoding, &msg) {
+                        // We'll find out about it properly from the other half:
+                        warn!("Failed to send {:?}: {:?}", msg, e);
+                        if !restorable { queue.push_back(msg.body) }
+                    }
+                },
+                Either::Right((Some(WsMsg::Msg(msg)), _)) => {
+                    if let ServerToClient::Welcome { client_id: id, session: new_token, resumed, .. } = &msg.body {
+                        client_id = Some(*id);
+                        token = Some(new_token.clone());
+                        for body in on_welcome(*resumed, &session, &mut synced, &mut queue) {
+                            let msg = ClientMsg { id: next_id, body };
+                            next_id += 1;
+                            if let Err(e) = websockets::send(&ws, protocol.encoding, &msg) {
+                                warn!("Failed to send {:?}: {:?}", msg, e);
+                            }
+                        }
+                        welcomed = true;
+                        welcomed_at = Some(js_sys::Date::now());
+                    } else if let Some(id) = client_id {
+                        synced.confirm(id, &msg.body);
+                        // There's no putting ourselves back in a room that's gone:
+                        if let ServerToClient::RoomDestroyed { room } = &msg.body {
+                            session.rooms.remove(room);
+                        }
+                    }
+                    events(ConnEvent::Msg(msg))
+                },
+                Either::Right((Some(WsMsg::Err(())), _)) => (),
+                Either::Right((Some(WsMsg::Closed(code)), _)) => {
+                    warn!("Connection closed with code {}", code);
+                    websockets::forget(&ws);
+                    close_code = Some(code);
+                    break
+                },
+                Either::Right((None, _)) => {
+                    websockets::forget(&ws);
+                    break
+                },
+            }
+        }
+
+        if close_code == Some(POLICY_VIOLATION) {
+            warn!("Server won't have us back, giving up");
+            return events(ConnEvent::State(ConnState::Closed));
+        }
+        let connected_for = welcomed_at.map(|t| Duration::from_millis((js_sys::Date::now() - t).max(0.0) as u64));
+        attempt = next_attempt(attempt, connected_for);
+        if attempt >= MAX_ATTEMPTS || !wait(attempt, &mut cmd_rx, &mut session, &mut queue, &mut events).await {
+            return events(ConnEvent::State(ConnState::Closed));
+        }
+    }
+}
+
+// Which attempt reconnecting after a connection that was up (and welcomed) for the given time will
+// be:
+fn next_attempt(attempt: u32, connected_for: Option<Duration>) -> u32 {
+    match connected_for {
+        Some(d) if d >= STABLE_AFTER => 1,
+        _ => attempt + 1,
+    }
+}
+
+// What to send once the server's welcomed us: first whatever it takes to bring the server's idea
+// of our session up to date, from scratch if it didn't keep the old one, then everything that got
+// queued up. Anything sent before that the server never confirmed gets sent again, which does no
+// harm if it turns out the server did get it.
+fn on_welcome(
+    resumed: bool,
+    session: &Session,
+    synced: &mut Session,
+    queue: &mut VecDeque<ClientToServer>,
+) -> Vec<ClientToServer> {
+    if !resumed {
+        *synced = Session::default();
+    }
+    let mut cmds = session.changes_since(synced);
+    cmds.extend(queue.drain(..));
+    cmds
+}
+
+fn with_session(url: &str, token: &str) -> String {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    format!("{}{}session={}", url, separator, token)
+}
+
+// Waits out the backoff before the given attempt, queueing any commands that turn up in the
+// meantime (other than the ones the session will restore anyway). Returns false if there's no
+// longer anybody sending us commands, so no point carrying on.
+async fn wait(
+    attempt: u32,
+    cmd_rx: &mut mpsc::Receiver<ClientToServer>,
+    session: &mut Session,
+    queue: &mut VecDeque<ClientToServer>,
+    events: &mut impl FnMut(ConnEvent),
+) -> bool {
+    events(ConnEvent::State(ConnState::Reconnecting { attempt }));
+    let delay = backoff(attempt, js_sys::Math::random());
+    info!("Reconnecting in {:?}", delay);
+    let mut timer = Box::pin(sleep(delay));
+    loop {
+        match future::select(timer, cmd_rx.next()).await {
+            Either::Left(((), _)) => return true,
+            Either::Right((None, _)) => return false,
+            Either::Right((Some(cmd), t)) => {
+                timer = t;
+                if !session.record(&cmd) { enqueue(queue, cmd) }
+            },
+        }
+    }
+}
+
+fn enqueue(queue: &mut VecDeque<ClientToServer>, cmd: ClientToServer) {
+    if queue.len() >= MAX_QUEUED {
+        // Not inside the warn!, which skips its arguments if warnings are turned off:
+        let dropped = queue.pop_front();
+        warn!("Too many commands queued up, dropping {:?}", dropped);
+    }
+    queue.push_back(cmd);
+}
+
+// Exponential backoff with "equal jitter": at least half the nominal delay, so we never hammer
+// the server, plus a random amount of the rest, so that a crowd of clients that all lost their
+// connection at once don't all come back at once too.
+fn backoff(attempt: u32, random: f64) -> Duration {
+    let nominal = BACKOFF_BASE.checked_mul(1 << attempt.saturating_sub(1).min(16))
+        .unwrap_or(BACKOFF_MAX)
+        .min(BACKOFF_MAX);
+    nominal / 2 + nominal.mul_f64(random.clamp(0.0, 1.0) / 2.0)
+}
+
+async fn sleep(d: Duration) {
+    let promise = js_sys::Promise::new(&mut |resolve, _| {
+        let window = web_sys::window().expect("no window");
+        if let Err(e) = window.set_timeout_with_callback_and_timeout_and_arguments_0(
+            &resolve, d.as_millis() as i32
+        ) {
+            let _ = resolve.call0(&JsValue::NULL);
+            warn!("Failed to set timeout: {:?}", e);
+        }
+    });
+    let _ = JsFuture::from(promise).await;
+}
+
+// The bits of state the server forgets about us when we disconnect, which we need to put back
+// when we reconnect:
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Session {
+    rooms: BTreeSet<RoomName>,
+    name: Option<String>,
+    metadata: Option<BTreeMap<String, String>>,
+}
+
+impl Session {
+    // Returns whether the command was one we'll restore on reconnecting:
+    fn record(&mut self, cmd: &ClientToServer) -> bool {
+        match cmd {
+            ClientToServer::JoinRoom { room } => { self.rooms.insert(room.clone()); },
+            ClientToServer::LeaveRoom { room } => { self.rooms.remove(room); },
+            ClientToServer::SetName { name } => self.name = name.clone(),
+            ClientToServer::SetMetadata { metadata } => self.metadata = Some(metadata.clone()),
+            _ => return false,
+        }
+        true
+    }
+
+    // Takes note of the server telling us (with the given id) that it's done what we asked:
+    fn confirm(&mut self, me: ClientId, body: &ServerToClient) {
+        match body {
+            ServerToClient::Joined { room, .. } => { self.rooms.insert(room.name.clone()); },
+            ServerToClient::Left { room } | ServerToClient::RoomDestroyed { room } => { self.rooms.remove(room); },
+            ServerToClient::ClientRenamed { client_id, name } if *client_id == me => self.name = name.clone(),
+            ServerToClient::ClientUpdated { client } if client.id == me => self.metadata = Some(client.metadata.clone()),
+            _ => (),
+        }
+    }
+
+    // The commands that would turn the old session into this one:
+    fn changes_since(&self, old: &Session) -> Vec<ClientToServer> {
+        let mut cmds = Vec::new();
+        if self.name != old.name {
+            cmds.push(ClientToServer::SetName { name: self.name.clone() });
+        }
+        if let Some(metadata) = self.metadata.as_ref().filter(|m| Some(*m) != old.metadata.as_ref()) {
+            cmds.push(ClientToServer::SetMetadata { metadata: metadata.clone() });
+        }
+        cmds.extend(old.rooms.difference(&self.rooms).map(|room| ClientToServer::LeaveRoom { room: room.clone() }));
+        cmds.extend(self.rooms.difference(&old.rooms).map(|room| ClientToServer::JoinRoom { room: room.clone() }));
+        cmds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        common::clapi::{Role, RoomInfo},
+    };
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1, 0.0), Duration::from_millis(250));
+        assert_eq!(backoff(1, 1.0), Duration::from_millis(500));
+        assert_eq!(backoff(3, 0.0), Duration::from_millis(1000));
+        assert_eq!(backoff(3, 0.5), Duration::from_millis(1500));
+        assert_eq!(backoff(20, 1.0), BACKOFF_MAX);
+        assert_eq!(backoff(u32::MAX, 0.0), BACKOFF_MAX / 2);
+    }
+
+    #[test]
+    fn test_next_attempt() {
+        assert_eq!(next_attempt(0, Some(STABLE_AFTER)), 1);
+        assert_eq!(next_attempt(5, Some(STABLE_AFTER * 2)), 1);
+        // Connections that drop straight after opening, or before we're even welcomed, are as good
+        // as failed:
+        assert_eq!(next_attempt(5, Some(Duration::from_secs(1))), 6);
+        assert_eq!(next_attempt(5, None), 6);
+    }
+
+    #[test]
+    fn test_enqueue() {
+        let mut queue = VecDeque::new();
+        for _ in 0..MAX_QUEUED {
+            enqueue(&mut queue, ClientToServer::ListRooms);
+        }
+        let last = ClientToServer::FetchHistory { room: "a".to_string(), since: None };
+        enqueue(&mut queue, last.clone());
+        // The oldest goes to make room:
+        assert_eq!(queue.len(), MAX_QUEUED);
+        assert_eq!(queue.back(), Some(&last));
+    }
+
+    #[test]
+    fn test_with_session() {
+        assert_eq!(with_session("ws://host/", "abc"), "ws://host/?session=abc");
+        assert_eq!(with_session("ws://host/?x=1", "abc"), "ws://host/?x=1&session=abc");
+    }
+
+    #[test]
+    fn test_session() {
+        let mut session = Session::default();
+        session.record(&ClientToServer::JoinRoom { room: "a".to_string() });
+        session.record(&ClientToServer::JoinRoom { room: "b".to_string() });
+        session.record(&ClientToServer::LeaveRoom { room: "a".to_string() });
+        session.record(&ClientToServer::SetName { name: Some("me".to_string()) });
+        assert!(!session.record(&ClientToServer::ListRooms));
+        assert_eq!(session.changes_since(&Session::default()), vec![
+            ClientToServer::SetName { name: Some("me".to_string()) },
+            ClientToServer::JoinRoom { room: "b".to_string() },
+        ]);
+
+        let mut newer = Session::default();
+        newer.record(&ClientToServer::JoinRoom { room: "c".to_string() });
+        assert_eq!(newer.changes_since(&session), vec![
+            ClientToServer::SetName { name: None },
+            ClientToServer::LeaveRoom { room: "b".to_string() },
+            ClientToServer::JoinRoom { room: "c".to_string() },
+        ]);
+        assert_eq!(session.changes_since(&session), vec![]);
+    }
+
+    #[test]
+    fn test_confirm() {
+        let mut synced = Session::default();
+        let renamed = |client_id, name: &str| ServerToClient::ClientRenamed { client_id, name: Some(name.to_string()) };
+        // Only what the server says about us counts:
+        synced.confirm(1, &renamed(2, "them"));
+        assert_eq!(synced.name, None);
+        synced.confirm(1, &renamed(1, "me"));
+        assert_eq!(synced.name, Some("me".to_string()));
+        let joined = ServerToClient::Joined { room: RoomInfo { name: "a".to_string(), members: vec![1] }, role: Role::Owner };
+        synced.confirm(1, &joined);
+        assert!(synced.rooms.contains("a"));
+        synced.confirm(1, &ServerToClient::RoomDestroyed { room: "a".to_string() });
+        assert!(synced.rooms.is_empty());
+    }
+
+    #[test]
+    fn test_on_welcome() {
+        let join = |room: &str| ClientToServer::JoinRoom { room: room.to_string() };
+        let mut session = Session::default();
+        session.record(&join("a"));
+        let mut synced = session.clone();
+
+        // Joining a room while disconnected only gets recorded, not queued:
+        let mut queue = VecDeque::new();
+        assert!(session.record(&join("b")));
+        queue.push_back(ClientToServer::ListRooms);
+
+        // The server remembers we're in a, but has never heard of b:
+        assert_eq!(on_welcome(true, &session, &mut synced, &mut queue), vec![join("b"), ClientToServer::ListRooms]);
+        assert!(queue.is_empty());
+        // If the connection drops before the server says we're in, we ask again:
+        assert_eq!(on_welcome(true, &session, &mut synced, &mut queue), vec![join("b")]);
+        synced.confirm(1, &ServerToClient::Joined { room: RoomInfo { name: "b".to_string(), members: vec![1] }, role: Role::Editor });
+        assert_eq!(synced, session);
+        assert_eq!(on_welcome(true, &session, &mut synced, &mut queue), vec![]);
+
+        // A server that's forgotten us needs telling everything:
+        assert_eq!(on_welcome(false, &session, &mut synced, &mut queue), vec![join("a"), join("b")]);
+    }
+}
@@ -2,8 +2,12 @@ extern crate cfg_if;
 
 use {
     cfg_if::cfg_if,
-    futures::{stream::StreamExt, channel::mpsc},
+    common::clapi::{
+        ClientId, ClientInfo, ClientToServer, Encoding, Protocol, ServerMsg, ServerToClient,
+    },
+    futures::channel::mpsc,
     log::{error, info},
+    std::collections::BTreeMap,
     wasm_bindgen::prelude::*,
     wasm_bindgen_futures::spawn_local,
 };
@@ -21,9 +25,12 @@ cfg_if! {
     }
 }
 
+mod connection;
 mod utils;
 mod websockets;
 
+use connection::{ConnEvent, ConnState};
+
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
 // allocator.
 #[cfg(feature = "wee_alloc")]
@@ -35,42 +42,25 @@ extern "C" {
     fn alert(s: &str);
 }
 
+// Until there's some UI for picking one, everybody ends up in the same room:
+const ROOM: &str = "lobby";
+
 #[wasm_bindgen]
 pub fn main(websocket_url: &str) {
     utils::set_panic_hook();
     init_log();
     yew::initialize();
 
-    // FIXME: My borrowing-fu is weak, there may be a better way to keep the compiler happy:
-    let url = websocket_url.to_string();
-    spawn_local(async move {
-        let (ws, mut msg_rx) = websockets::go(&url).await.expect_throw("oops");
-        let (cmd_tx, mut cmd_rx) = mpsc::channel::<String>(32);
-        spawn_local(async move {
-            loop {
-                match cmd_rx.next().await {
-                    None => error!("oh noes"),
-                    Some(txt) => {
-                        info!("Send command received, sending message...");
-                        ws.send_with_str(&txt);
-                    }
-                }
-            }
-        });
-
-        let ui = yew::App::<UiModel>::new().mount_to_body_with_props(UiProps{ cmd_tx });
-        spawn_local(async move {
-            loop {
-                match msg_rx.next().await {
-                    None => error!("wat"),
-                    Some(websockets::WsMsg::Msg(msg)) => ui.send_message(UiMsg::ReceivedMsg(msg)),
-                    Some(websockets::WsMsg::Err(())) => error!("I died"),
-                }
-            }
-
-        });
-
-    });
+    let (mut cmd_tx, cmd_rx) = mpsc::channel::<ClientToServer>(32);
+    cmd_tx.try_send(ClientToServer::JoinRoom { room: ROOM.to_string() }).expect_throw("join failed");
+    let ui = yew::App::<UiModel>::new().mount_to_body_with_props(UiProps{ cmd_tx });
+    let offered = vec![Protocol::current(Encoding::Cbor), Protocol::current(Encoding::Json)];
+    spawn_local(connection::manage(websocket_url.to_string(), offered, cmd_rx, move |event| {
+        match event {
+            ConnEvent::State(state) => ui.send_message(UiMsg::ConnState(state)),
+            ConnEvent::Msg(msg) => ui.send_message(UiMsg::ReceivedMsg(msg)),
+        }
+    }));
     info!("hello again");
 }
 
@@ -81,15 +71,18 @@ struct UiModel {
 
 #[derive(Clone, yew::Properties)]
 struct UiProps {
-    cmd_tx: mpsc::Sender<String>,
+    cmd_tx: mpsc::Sender<ClientToServer>,
 }
 
 struct UiState {
-    received_count: u32
+    conn_state: ConnState,
+    received_count: u32,
+    collaborators: BTreeMap<ClientId, ClientInfo>,
 }
 
 enum UiMsg {
-    ReceivedMsg(String),
+    ConnState(ConnState),
+    ReceivedMsg(ServerMsg),
 }
 
 impl yew::Component for UiModel {
@@ -97,15 +90,57 @@ impl yew::Component for UiModel {
     type Properties = UiProps;
 
     fn create(props: Self::Properties, _: yew::ComponentLink<Self>) -> Self {
-        Self { props, state: UiState{ received_count: 0 } }
+        Self { props, state: UiState {
+            conn_state: ConnState::Connecting,
+            received_count: 0,
+            collaborators: BTreeMap::new(),
+        } }
     }
 
     fn update(&mut self, msg: Self::Message) -> yew::ShouldRender {
         match msg {
-            UiMsg::ReceivedMsg(msg) => {
-                info!("UI received a message! {}", msg);
-                self.state.received_count += 1;
+            UiMsg::ConnState(state) => {
+                self.state.conn_state = state;
                 true
+            },
+            UiMsg::ReceivedMsg(msg) => {
+                info!("UI received a message! {:?}", msg);
+                match msg.body {
+                    ServerToClient::Broadcast { .. } | ServerToClient::Direct { .. }
+                    | ServerToClient::Announcement { .. } => {
+                        self.state.received_count += 1;
+                        true
+                    },
+                    ServerToClient::Presence { clients } => {
+                        self.state.collaborators = clients.into_iter().map(|c| (c.id, c)).collect();
+                        true
+                    },
+                    ServerToClient::ClientJoined { client } | ServerToClient::ClientUpdated { client } => {
+                        self.state.collaborators.insert(client.id, client);
+                        true
+                    },
+                    ServerToClient::ClientLeft { client_id } => {
+                        self.state.collaborators.remove(&client_id);
+                        true
+                    },
+                    ServerToClient::ClientRenamed { client_id, name } => {
+                        if let Some(client) = self.state.collaborators.get_mut(&client_id) {
+                            client.name = name;
+                        }
+                        true
+                    },
+                    ServerToClient::Reload => {
+                        info!("Server has a new build of the client, reloading...");
+                        let reloaded = web_sys::window().map(|w| w.location().reload());
+                        if let Some(Err(e)) = reloaded { error!("Failed to reload: {:?}", e) }
+                        false
+                    },
+                    ServerToClient::Error { error } => {
+                        error!("Server says we made a mistake: {}", error);
+                        false
+                    },
+                    _ => false
+                }
             }
         }
     }
@@ -118,12 +153,28 @@ impl yew::Component for UiModel {
         yew::html! {
             <div>
               <h1>{ "Hello World: " }<Counter n=self.state.received_count/></h1>
+              <p>{ view_conn_state(self.state.conn_state) }</p>
               <Transmitter default_msg="Hello World!" cmd_tx=self.props.cmd_tx.clone()/>
+              <ul>{ for self.state.collaborators.values().map(view_collaborator) }</ul>
             </div>
         }
     }
 }
 
+fn view_conn_state(state: ConnState) -> String {
+    match state {
+        ConnState::Connecting => "Connecting…".to_string(),
+        ConnState::Open => "Connected".to_string(),
+        ConnState::Reconnecting { attempt } => format!("Reconnecting (attempt {})…", attempt),
+        ConnState::Closed => "Disconnected, reload the page to try again".to_string(),
+    }
+}
+
+fn view_collaborator(c: &ClientInfo) -> yew::Html {
+    let name = c.name.clone().or_else(|| c.user.clone()).unwrap_or_else(|| format!("Anonymous #{}", c.id));
+    yew::html! { <li>{ name }</li> }
+}
+
 #[repr(transparent)]
 #[derive(Clone, PartialEq, yew::Properties)]
 struct U32Prop { n: u32 }
@@ -169,7 +220,7 @@ struct Transmitter {
 #[derive(Clone, yew::Properties)]
 struct TransmitterProps {
     default_msg: String,
-    cmd_tx: mpsc::Sender<String>,
+    cmd_tx: mpsc::Sender<ClientToServer>,
 }
 
 #[derive(Debug)]
@@ -200,11 +251,16 @@ impl yew::Component for Transmitter {
     fn update(&mut self, msg: Self::Message) -> yew::ShouldRender {
         match msg {
             TransmitterMsg::Input(txt) => {
-                self.current_msg = if txt == "" { None } else { Some(txt) };
+                self.current_msg = if txt.is_empty() { None } else { Some(txt) };
             },
             TransmitterMsg::SendMsg => {
                 let current_msg = self.current_msg.take();
-                self.props.cmd_tx.try_send(self.msg(&current_msg));
+                if let Err(e) = self.props.cmd_tx.try_send(ClientToServer::Broadcast {
+                    room: ROOM.to_string(),
+                    text: self.msg(&current_msg),
+                }) {
+                    error!("Failed to send message: {}", e);
+                }
             }
         }
         true
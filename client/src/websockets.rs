@@ -3,18 +3,20 @@ use {
         channel::mpsc,
         stream::StreamExt,
     },
-    js_sys,
+    common::clapi::{ClientMsg, Codec, Encoding, Protocol, ServerMsg},
     log::{error, warn, info},
     std::fmt::Debug,
     thiserror::Error,
     wasm_bindgen::{convert::FromWasmAbi, prelude::*, JsCast},
-    web_sys::{ErrorEvent, MessageEvent, WebSocket},
+    web_sys::{CloseEvent, ErrorEvent, MessageEvent, WebSocket},
 };
 
-pub async fn go<'a>(url: &'a str) -> Result<(WebSocket, mpsc::Receiver<WsMsg>), WsError<'a>> {
-    let protocol = &format!("clapi-{}-{}", common::VERSION.major, common::VERSION.minor);
+// Offers the server each of the given protocols, in order of preference, and returns the one it
+// picked along with the connection.
+pub async fn go<'a>(url: &'a str, offered: &[Protocol]) -> Result<(WebSocket, Protocol, mpsc::Receiver<WsMsg>), WsError<'a>> {
+    let protocols: js_sys::Array = offered.iter().map(|p| JsValue::from(p.to_string())).collect();
     let (rcv_tx, rcv_rx) = mpsc::channel(32);
-    let ws = WebSocket::new_with_str(url, protocol)
+    let ws = WebSocket::new_with_str_sequence(url, &protocols)
         .map_err(|e| WsError::ConnectionFailed{ url, err: e }
     )?;
     ws.set_binary_type(web_sys::BinaryType::Arraybuffer);
@@ -24,17 +26,23 @@ pub async fn go<'a>(url: &'a str) -> Result<(WebSocket, mpsc::Receiver<WsMsg>),
         |cb| ws.set_onmessage(cb),
         move |e: MessageEvent| {
             info!("onmessage: {:?} {:?}", e, e.data());
-            if let Ok(msg) = e.data().dyn_into::<js_sys::JsString>() {
-                send_mpsc(&mut tx, WsMsg::Msg(msg.into()))
+            let decoded = if let Ok(txt) = e.data().dyn_into::<js_sys::JsString>() {
+                ServerMsg::from_text(&String::from(txt))
+            } else if let Ok(buf) = e.data().dyn_into::<js_sys::ArrayBuffer>() {
+                ServerMsg::from_binary(&js_sys::Uint8Array::new(&buf).to_vec())
             } else {
-                error!("error unpacking message!")
+                error!("error unpacking message!");
+                return
+            };
+            match decoded {
+                Ok(msg) => send_mpsc(&mut tx, WsMsg::Msg(msg)),
+                Err(err) => error!("error decoding message: {}", err),
             }
         }
     );
 
-    // FIXME: The JS WebSockets API appears to send connection errors to this error handler, so we
-    // should set a handler like the onopen one, then switch to this one once our connection is
-    // established:
+    // Connection errors turn up here too, but they're always followed by a close, which is what we
+    // actually act on:
     let mut tx = rcv_tx.clone();
     set_callback(
         |cb| ws.set_onerror(cb),
@@ -45,42 +53,84 @@ pub async fn go<'a>(url: &'a str) -> Result<(WebSocket, mpsc::Receiver<WsMsg>),
     );
 
     // TODO: This was supposed to be a futures::channel::oneshot, but it didn't type check with
-    // onshot::Sender.send() in the closure for unknown reasons:
-    let (mut connected_tx, mut connected_rx) = mpsc::channel(1);
+    // onshot::Sender.send() in the closure for unknown reasons. Whichever of onopen and onclose
+    // fires first tells us whether we managed to connect:
+    let (connected_tx, mut connected_rx) = mpsc::channel(1);
+    let mut open_tx = connected_tx.clone();
     set_callback(
         |cb| ws.set_onopen(cb),
-        move |e: JsValue| {
-            send_mpsc(&mut connected_tx, ())
+        move |_: JsValue| {
+            let _ = open_tx.try_send(true);
         }
     );
-    connected_rx.next().await;
+    let mut closed_tx = connected_tx;
+    let mut tx = rcv_tx;
+    set_callback(
+        |cb| ws.set_onclose(cb),
+        move |e: CloseEvent| {
+            info!("onclose: {} {:?}", e.code(), e.reason());
+            let _ = closed_tx.try_send(false);
+            let _ = tx.try_send(WsMsg::Closed(e.code()));
+        }
+    );
+    let connected = connected_rx.next().await.unwrap_or(false);
     connected_rx.close();
-    // FIXME: Actual error handling!
+    if !connected {
+        forget(&ws);
+        return Err(WsError::Closed)
+    }
     if ws.ready_state() != WebSocket::OPEN { warn!("WebSocket not in open state!") }
-    if &ws.protocol() != protocol { error!("not speaking the right protocol!") }
-    Ok((ws, rcv_rx))
+    match Protocol::parse(&ws.protocol()) {
+        Some(protocol) if offered.contains(&protocol) => Ok((ws, protocol, rcv_rx)),
+        _ => {
+            let _ = ws.close();
+            forget(&ws);
+            Err(WsError::UnexpectedProtocol(ws.protocol()))
+        },
+    }
+}
+
+// Unhooks all our callbacks, so that a connection we've given up on can't send us anything else:
+pub fn forget(ws: &WebSocket) {
+    ws.set_onopen(None);
+    ws.set_onmessage(None);
+    ws.set_onerror(None);
+    ws.set_onclose(None);
+}
+
+pub fn send(ws: &WebSocket, encoding: Encoding, msg: &ClientMsg) -> Result<(), JsValue> {
+    match encoding {
+        Encoding::Json => ws.send_with_str(&msg.to_text()),
+        Encoding::Cbor => ws.send_with_u8_array(&msg.to_binary()),
+    }
 }
 
 #[derive(Clone)]
 pub enum WsMsg {
-    Msg(String),
+    Msg(ServerMsg),
     Err(()),
+    // With the close code. We won't hear anything more from the connection after this:
+    Closed(u16),
 }
 
 #[derive(Debug, Error)]
 pub enum WsError<'a> {
     #[error("Failed to {url} connect: {err:?}")]
     ConnectionFailed{ url: &'a str, err: JsValue },
+    #[error("Connection closed before it opened")]
+    Closed,
+    #[error("Server chose a protocol we didn't offer: {0:?}")]
+    UnexpectedProtocol(String),
 }
 
 // Wraps up a lot of boilerplate closure wrappy stuff by adding even more confusing types! However,
 // it keeps all the "stuff to remember" in one place and should hopefully make the client code
 // easier to read.
-fn set_callback<Evt: 'static, S, CB: 'static>(setter: S, cb: CB)
+fn set_callback<Evt, S, CB>(setter: S, cb: CB)
 where
-    Evt: FromWasmAbi,
+    Evt: FromWasmAbi + 'static,
     S: Fn(Option<&js_sys::Function>), // TODO: nicer way to pass WebSocket method?
-    CB: FnMut(Evt),
+    CB: FnMut(Evt) + 'static,
 {
     let cb = Closure::wrap(Box::new(cb) as Box<dyn FnMut(Evt)>);
     setter(Some(cb.as_ref().unchecked_ref()));
@@ -0,0 +1,576 @@
+use {
+    serde::{Deserialize, Serialize, de::DeserializeOwned},
+    std::{collections::BTreeMap, fmt},
+};
+
+use crate::VERSION;
+
+pub type ClientId = u16;
+pub type RequestId = u32;
+pub type RoomName = String;
+// Each room numbers its broadcasts in order, starting from 0:
+pub type Seq = u64;
+// Opaque to clients, who just hand it back when reconnecting to pick up where they left off:
+pub type SessionToken = String;
+
+// Text frames always carry JSON, binary frames always carry CBOR. Which of the two a server
+// sends to a given client is settled during the handshake by the choice of subprotocol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    Cbor,
+}
+
+// The websocket subprotocol that both ends have to agree on, e.g. "clapi-0-1" or
+// "clapi-0-1+cbor". Only the major and minor version numbers go in, as patch releases mustn't
+// change the shape of any of the messages below.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Protocol {
+    pub major: u64,
+    pub minor: u64,
+    pub encoding: Encoding,
+}
+
+impl Protocol {
+    pub fn current(encoding: Encoding) -> Self {
+        Protocol { major: VERSION.major, minor: VERSION.minor, encoding }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        let (version, encoding) = match s.find('+') {
+            None => (s, Encoding::Json),
+            Some(i) => match &s[i + 1..] {
+                "cbor" => (&s[..i], Encoding::Cbor),
+                _ => return None,
+            },
+        };
+        let mut parts = version.split('-');
+        if parts.next() != Some("clapi") { return None }
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next()?.parse().ok()?;
+        if parts.next().is_some() { return None }
+        Some(Protocol { major, minor, encoding })
+    }
+
+    pub fn is_supported(&self) -> bool {
+        self.is_compatible_with(VERSION.major, VERSION.minor)
+    }
+
+    // Minor versions only ever add to the protocol, so we can keep talking to clients that were
+    // built against an older minor version of the same major version (e.g. mid rolling upgrade),
+    // who skip over any messages that are newer than they are. Before 1.0 though, as per semver,
+    // every minor version is free to break things.
+    fn is_compatible_with(&self, major: u64, minor: u64) -> bool {
+        match major {
+            0 => self.major == 0 && self.minor == minor,
+            _ => self.major == major && self.minor <= minor,
+        }
+    }
+
+    // Picks the newest supported protocol from those offered by a client. Where a client offers
+    // the same version in several encodings, the one it listed first wins.
+    pub fn negotiate<'a, I: IntoIterator<Item = &'a str>>(offered: I) -> Option<Self> {
+        offered.into_iter()
+            .filter_map(Protocol::parse)
+            .filter(Protocol::is_supported)
+            .fold(None, |best: Option<Protocol>, p| match best {
+                Some(b) if (b.major, b.minor) >= (p.major, p.minor) => Some(b),
+                _ => Some(p),
+            })
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "clapi-{}-{}", self.major, self.minor)?;
+        match self.encoding {
+            Encoding::Json => Ok(()),
+            Encoding::Cbor => f.write_str("+cbor"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClientMsg {
+    pub id: RequestId,
+    pub body: ClientToServer,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ServerMsg {
+    // The id of the ClientMsg that this is a response to, if any:
+    pub re: Option<RequestId>,
+    pub body: ServerToClient,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ClientToServer {
+    Broadcast { room: RoomName, text: String },
+    // Sent to just the one client, who needn't share a room with the sender:
+    Direct { to: Recipient, text: String },
+    JoinRoom { room: RoomName },
+    LeaveRoom { room: RoomName },
+    // Only empty rooms can be destroyed, and only by their owners if they have any:
+    DestroyRoom { room: RoomName },
+    ListRooms,
+    // Whatever the room still remembers from after the given message, or everything it remembers
+    // if none is given. Only members can see a room's history:
+    FetchHistory { room: RoomName, since: Option<Seq> },
+    // Names have to be unique amongst connected clients:
+    SetName { name: Option<String> },
+    SetMetadata { metadata: BTreeMap<String, String> },
+    // Only a room's owners can change who has what role in it:
+    GrantRole { room: RoomName, to: Grantee, role: Role },
+    RevokeRole { room: RoomName, from: Grantee },
+    // The role of anyone who hasn't been granted one:
+    SetDefaultRole { room: RoomName, role: Role },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ServerToClient {
+    // A client that reconnects with the session token in time gets its old id back (resumed is
+    // then true), followed by anything it missed while it was away. Tokens are single use: each
+    // Welcome comes with a fresh one.
+    Welcome { client_id: ClientId, version: String, session: SessionToken, resumed: bool },
+    // Everyone who's connected, sent straight after the Welcome:
+    Presence { clients: Vec<ClientInfo> },
+    ClientJoined { client: ClientInfo },
+    ClientLeft { client_id: ClientId },
+    ClientRenamed { client_id: ClientId, name: Option<String> },
+    ClientUpdated { client: ClientInfo },
+    Broadcast { room: RoomName, from: ClientId, text: String, seq: Seq },
+    Direct { from: ClientId, text: String },
+    // From whoever's running the server, to a single room or (with no room) everybody:
+    Announcement { room: Option<RoomName>, text: String },
+    // Tells the sender of a Direct message that it's been passed on to the recipient:
+    Delivered { to: ClientId },
+    // Followed by the room's recent History. The role is what the joiner has in the room:
+    Joined { room: RoomInfo, role: Role },
+    Left { room: RoomName },
+    Rooms { rooms: Vec<RoomInfo> },
+    History { room: RoomName, messages: Vec<HistoryEntry> },
+    // Room lifecycle events go to every client, whether or not they're in the room:
+    RoomCreated { room: RoomName },
+    RoomEmptied { room: RoomName },
+    RoomDestroyed { room: RoomName },
+    // Role changes go to the room's members. A role of None means the grant was revoked:
+    RoleChanged { room: RoomName, grantee: Grantee, role: Option<Role> },
+    DefaultRoleChanged { room: RoomName, role: Role },
+    // Only sent by servers in dev mode, when a new build of the client is available:
+    Reload,
+    Error { error: ProtocolError },
+    // Anything added in a newer minor version than this end knows about, which is best ignored:
+    #[serde(other)]
+    Unknown,
+}
+
+// Names are whatever clients choose to call themselves, so only users that the server has
+// authenticated are a safe bet for anything private. A user gets messages on all of its clients.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Recipient {
+    Id(ClientId),
+    Name(String),
+    User(String),
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Recipient::Id(id) => write!(f, "#{}", id),
+            Recipient::Name(name) => f.write_str(name),
+            Recipient::User(user) => write!(f, "user {}", user),
+        }
+    }
+}
+
+// In increasing order of what they allow: viewers can only watch, editors can broadcast too and
+// owners can also manage roles and destroy the room.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Role {
+    Viewer,
+    Editor,
+    Owner,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Owner => "owner",
+        })
+    }
+}
+
+// Roles granted to a user follow them wherever they connect from. Ones granted to a client only
+// last as long as it does, which is all there is for clients that haven't been authenticated.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Grantee {
+    User(String),
+    Client(ClientId),
+}
+
+impl fmt::Display for Grantee {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Grantee::User(user) => f.write_str(user),
+            Grantee::Client(id) => write!(f, "#{}", id),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub id: ClientId,
+    // Who the server knows the client to be, if it's been authenticated:
+    pub user: Option<String>,
+    // Whatever the client likes to call itself:
+    pub name: Option<String>,
+    pub metadata: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub name: RoomName,
+    pub members: Vec<ClientId>,
+}
+
+// A broadcast, as remembered by the room it was sent to:
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub seq: Seq,
+    pub from: ClientId,
+    pub text: String,
+    // The user the sender was logged in as, or failing that the name they went by. Client ids
+    // start again from 0 whenever the server restarts, so for anything sent before then `from`
+    // is likely someone else by now, but this isn't.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum ProtocolError {
+    Malformed { reason: String },
+    NoSuchRoom { room: RoomName },
+    NotInRoom { room: RoomName },
+    RoomNotEmpty { room: RoomName },
+    NameTaken { name: String },
+    RecipientNotFound { recipient: Recipient },
+    // Doing that needs at least the given role in the room:
+    Forbidden { room: RoomName, needs: Role },
+    // The message was dropped for going over the client's rate limits. Clients that keep at it
+    // get disconnected:
+    SlowDown { retry_after_ms: u64 },
+    // Whatever the error was, it was added in a newer minor version than this end knows about:
+    #[serde(other)]
+    Unknown,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::Malformed { reason } => write!(f, "malformed message: {}", reason),
+            ProtocolError::NoSuchRoom { room } => write!(f, "no such room: {}", room),
+            ProtocolError::NotInRoom { room } => write!(f, "not in room: {}", room),
+            ProtocolError::RoomNotEmpty { room } => write!(f, "room not empty: {}", room),
+            ProtocolError::NameTaken { name } => write!(f, "name already taken: {}", name),
+            ProtocolError::RecipientNotFound { recipient } =>
+                write!(f, "recipient not found: {}", recipient),
+            ProtocolError::Forbidden { room, needs } =>
+                write!(f, "only {}s can do that in room: {}", needs, room),
+            ProtocolError::SlowDown { retry_after_ms } =>
+                write!(f, "too many messages, try again in {}ms", retry_after_ms),
+            ProtocolError::Unknown => f.write_str("unknown error"),
+        }
+    }
+}
+
+// The names that go in the "type" field, for when we want to talk about messages without all
+// their contents (e.g. in logs and metrics):
+impl ClientToServer {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClientToServer::Broadcast { .. } => "Broadcast",
+            ClientToServer::Direct { .. } => "Direct",
+            ClientToServer::JoinRoom { .. } => "JoinRoom",
+            ClientToServer::LeaveRoom { .. } => "LeaveRoom",
+            ClientToServer::DestroyRoom { .. } => "DestroyRoom",
+            ClientToServer::ListRooms => "ListRooms",
+            ClientToServer::FetchHistory { .. } => "FetchHistory",
+            ClientToServer::SetName { .. } => "SetName",
+            ClientToServer::SetMetadata { .. } => "SetMetadata",
+            ClientToServer::GrantRole { .. } => "GrantRole",
+            ClientToServer::RevokeRole { .. } => "RevokeRole",
+            ClientToServer::SetDefaultRole { .. } => "SetDefaultRole",
+        }
+    }
+}
+
+impl ServerToClient {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ServerToClient::Welcome { .. } => "Welcome",
+            ServerToClient::Presence { .. } => "Presence",
+            ServerToClient::ClientJoined { .. } => "ClientJoined",
+            ServerToClient::ClientLeft { .. } => "ClientLeft",
+            ServerToClient::ClientRenamed { .. } => "ClientRenamed",
+            ServerToClient::ClientUpdated { .. } => "ClientUpdated",
+            ServerToClient::Broadcast { .. } => "Broadcast",
+            ServerToClient::Direct { .. } => "Direct",
+            ServerToClient::Announcement { .. } => "Announcement",
+            ServerToClient::Delivered { .. } => "Delivered",
+            ServerToClient::Joined { .. } => "Joined",
+            ServerToClient::Left { .. } => "Left",
+            ServerToClient::Rooms { .. } => "Rooms",
+            ServerToClient::History { .. } => "History",
+            ServerToClient::RoomCreated { .. } => "RoomCreated",
+            ServerToClient::RoomEmptied { .. } => "RoomEmptied",
+            ServerToClient::RoomDestroyed { .. } => "RoomDestroyed",
+            ServerToClient::RoleChanged { .. } => "RoleChanged",
+            ServerToClient::DefaultRoleChanged { .. } => "DefaultRoleChanged",
+            ServerToClient::Reload => "Reload",
+            ServerToClient::Error { .. } => "Error",
+            ServerToClient::Unknown => "Unknown",
+        }
+    }
+}
+
+impl ServerMsg {
+    pub fn reply(re: RequestId, body: ServerToClient) -> Self {
+        ServerMsg { re: Some(re), body }
+    }
+
+    pub fn event(body: ServerToClient) -> Self {
+        ServerMsg { re: None, body }
+    }
+
+    pub fn error(re: Option<RequestId>, error: ProtocolError) -> Self {
+        ServerMsg { re, body: ServerToClient::Error { error } }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodeError(pub String);
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<DecodeError> for ProtocolError {
+    fn from(e: DecodeError) -> Self {
+        ProtocolError::Malformed { reason: e.0 }
+    }
+}
+
+pub trait Codec: Serialize + DeserializeOwned {
+    fn to_text(&self) -> String {
+        serde_json::to_string(self).expect("clapi messages should always serialise")
+    }
+
+    fn from_text(s: &str) -> Result<Self, DecodeError> {
+        serde_json::from_str(s).map_err(|e| DecodeError(e.to_string()))
+    }
+
+    fn to_binary(&self) -> Vec<u8> {
+        serde_cbor::to_vec(self).expect("clapi messages should always serialise")
+    }
+
+    fn from_binary(b: &[u8]) -> Result<Self, DecodeError> {
+        serde_cbor::from_slice(b).map_err(|e| DecodeError(e.to_string()))
+    }
+}
+
+impl Codec for ClientMsg {}
+impl Codec for ServerMsg {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_round_trip() {
+        let msg = ClientMsg {
+            id: 3,
+            body: ClientToServer::Broadcast { room: "lobby".to_string(), text: "hi".to_string() }
+        };
+        assert_eq!(
+            msg.to_text(),
+            r#"{"id":3,"body":{"type":"Broadcast","room":"lobby","text":"hi"}}"#
+        );
+        assert_eq!(ClientMsg::from_text(&msg.to_text()), Ok(msg));
+
+        let msg = ServerMsg::error(Some(3), ProtocolError::Malformed { reason: "eh?".to_string() });
+        assert_eq!(ServerMsg::from_text(&msg.to_text()), Ok(msg));
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let msgs = vec![
+            ServerMsg::event(ServerToClient::Welcome {
+                client_id: 7, version: VERSION.to_string(), session: "abc".to_string(), resumed: false
+            }),
+            ServerMsg::event(ServerToClient::Broadcast {
+                room: "lobby".to_string(), from: 7, text: "ünïcödé".to_string(), seq: 12
+            }),
+            ServerMsg::reply(4, ServerToClient::History { room: "lobby".to_string(), messages: vec![
+                HistoryEntry { seq: 11, from: 3, text: "hi".to_string(), sender: Some("paul".to_string()) },
+            ]}),
+            ServerMsg::reply(2, ServerToClient::Joined {
+                room: RoomInfo { name: "lobby".to_string(), members: vec![3, 7] },
+                role: Role::Viewer,
+            }),
+            ServerMsg::event(ServerToClient::RoleChanged {
+                room: "lobby".to_string(), grantee: Grantee::User("paul".to_string()), role: Some(Role::Owner)
+            }),
+            ServerMsg::error(Some(5), ProtocolError::Forbidden { room: "lobby".to_string(), needs: Role::Editor }),
+            ServerMsg::error(None, ProtocolError::Malformed { reason: "eh?".to_string() }),
+            ServerMsg::event(ServerToClient::Presence { clients: vec![
+                ClientInfo { id: 3, user: None, name: None, metadata: BTreeMap::new() },
+                ClientInfo {
+                    id: 7,
+                    user: Some("paul".to_string()),
+                    name: Some("Paul".to_string()),
+                    metadata: vec![("colour".to_string(), "green".to_string())].into_iter().collect(),
+                },
+            ]}),
+        ];
+        for msg in msgs {
+            assert_eq!(ServerMsg::from_binary(&msg.to_binary()), Ok(msg.clone()));
+            assert_eq!(
+                ServerMsg::from_binary(&msg.to_binary()),
+                ServerMsg::from_text(&msg.to_text())
+            );
+        }
+    }
+
+    #[test]
+    fn test_kind() {
+        // kind() has to agree with what serde puts in the "type" field:
+        let type_field = |v: serde_json::Value| v["type"].as_str().unwrap().to_string();
+        let msgs = vec![
+            ClientToServer::ListRooms,
+            ClientToServer::SetName { name: None },
+            ClientToServer::RevokeRole { room: "lobby".to_string(), from: Grantee::Client(3) },
+        ];
+        for msg in msgs {
+            assert_eq!(type_field(serde_json::to_value(&msg).unwrap()), msg.kind());
+        }
+        let msgs = vec![
+            ServerToClient::Reload,
+            ServerToClient::Announcement { room: None, text: "hi".to_string() },
+        ];
+        for msg in msgs {
+            assert_eq!(type_field(serde_json::to_value(&msg).unwrap()), msg.kind());
+        }
+    }
+
+    #[test]
+    fn test_recipient() {
+        let msg = ClientMsg {
+            id: 5,
+            body: ClientToServer::Direct { to: Recipient::Name("Paul".to_string()), text: "psst".to_string() }
+        };
+        assert_eq!(
+            msg.to_text(),
+            r#"{"id":5,"body":{"type":"Direct","to":{"Name":"Paul"},"text":"psst"}}"#
+        );
+        assert_eq!(ClientMsg::from_binary(&msg.to_binary()), Ok(msg));
+    }
+
+    #[test]
+    fn test_malformed() {
+        assert!(ClientMsg::from_text("Hello World!").is_err());
+        assert!(ClientMsg::from_text(r#"{"id":3,"body":{"type":"Nope"}}"#).is_err());
+        assert!(ClientMsg::from_binary(b"Hello World!").is_err());
+    }
+
+    #[test]
+    fn test_newer_messages() {
+        // What an older client makes of messages that a newer server might send it:
+        let msg = ServerMsg::from_text(r#"{"re":null,"body":{"type":"Teleported","to":"moon"}}"#).unwrap();
+        assert_eq!(msg.body, ServerToClient::Unknown);
+        let msg = ServerMsg::from_text(r#"{"re":1,"body":{"type":"Error","error":{"kind":"TooSilly"}}}"#).unwrap();
+        assert_eq!(msg.body, ServerToClient::Error { error: ProtocolError::Unknown });
+        // New fields on messages we do know about get ignored too:
+        let msg = ServerMsg::from_text(r#"{"re":null,"body":{"type":"Left","room":"a","why":"bored"}}"#).unwrap();
+        assert_eq!(msg.body, ServerToClient::Left { room: "a".to_string() });
+
+        #[derive(Serialize)]
+        struct Newer { re: Option<RequestId>, body: NewerBody }
+        #[derive(Serialize)]
+        #[serde(tag = "type")]
+        enum NewerBody { Teleported { to: String } }
+        let cbor = serde_cbor::to_vec(&Newer { re: None, body: NewerBody::Teleported { to: "moon".to_string() } }).unwrap();
+        assert_eq!(ServerMsg::from_binary(&cbor).unwrap().body, ServerToClient::Unknown);
+    }
+
+    #[test]
+    fn test_protocol_parse() {
+        for encoding in [Encoding::Json, Encoding::Cbor].iter() {
+            let current = Protocol::current(*encoding);
+            assert_eq!(Protocol::parse(&current.to_string()), Some(current));
+        }
+        assert_eq!(
+            Protocol::parse("clapi-3-12+cbor"),
+            Some(Protocol { major: 3, minor: 12, encoding: Encoding::Cbor })
+        );
+        assert_eq!(Protocol::parse("clapi-0-0+msgpack"), None);
+        assert_eq!(Protocol::parse("clapi-0"), None);
+        assert_eq!(Protocol::parse("clapi-0-1-2"), None);
+        assert_eq!(Protocol::parse("chat"), None);
+    }
+
+    #[test]
+    fn test_protocol_negotiate() {
+        let p = |major, minor, encoding| Protocol { major, minor, encoding }.to_string();
+        let (major, minor) = (VERSION.major, VERSION.minor);
+        let newer = p(major, minor + 1, Encoding::Cbor);
+        let current_json = p(major, minor, Encoding::Json);
+        let current_cbor = p(major, minor, Encoding::Cbor);
+        let next_major = p(major + 1, 0, Encoding::Json);
+
+        assert_eq!(
+            Protocol::negotiate(vec![newer.as_str(), "chat", current_json.as_str(), current_cbor.as_str()]),
+            Some(Protocol::current(Encoding::Json))
+        );
+        assert_eq!(
+            Protocol::negotiate(vec![current_cbor.as_str(), current_json.as_str()]),
+            Some(Protocol::current(Encoding::Cbor))
+        );
+        assert_eq!(Protocol::negotiate(vec![newer.as_str(), next_major.as_str()]), None);
+        assert_eq!(Protocol::negotiate(vec![]), None);
+        if major == 0 && minor > 0 {
+            let older = p(major, minor - 1, Encoding::Json);
+            assert_eq!(Protocol::negotiate(vec![older.as_str()]), None);
+        }
+        if major > 0 && minor > 0 {
+            let older = p(major, minor - 1, Encoding::Json);
+            assert_eq!(
+                Protocol::negotiate(vec![older.as_str(), current_cbor.as_str()]),
+                Some(Protocol::current(Encoding::Cbor))
+            );
+            assert_eq!(Protocol::negotiate(vec![older.as_str()]).map(|p| p.minor), Some(minor - 1));
+        }
+    }
+
+    #[test]
+    fn test_protocol_compatibility() {
+        let p = |major, minor| Protocol { major, minor, encoding: Encoding::Json };
+        // Pre-1.0 minor versions can't be mixed:
+        assert!(p(0, 2).is_compatible_with(0, 2));
+        assert!(!p(0, 1).is_compatible_with(0, 2));
+        assert!(!p(0, 3).is_compatible_with(0, 2));
+        assert!(!p(1, 2).is_compatible_with(0, 2));
+        // After that, older minor versions of the same major version are fine:
+        assert!(p(1, 2).is_compatible_with(1, 2));
+        assert!(p(1, 0).is_compatible_with(1, 2));
+        assert!(!p(1, 3).is_compatible_with(1, 2));
+        assert!(!p(0, 2).is_compatible_with(1, 2));
+        assert!(!p(2, 0).is_compatible_with(1, 2));
+    }
+}
@@ -4,4 +4,6 @@ use {
 
 use macros::cargo_pkg_version;
 
+pub mod clapi;
+
 pub const VERSION: Version = cargo_pkg_version!();
@@ -4,7 +4,7 @@ use {
     quote::{quote, ToTokens},
     syn::{
         Expr, ExprLit,
-        Lit, LitStr,
+        Lit, LitByteStr, LitStr,
         parse::Parser,
         punctuated::Punctuated,
         token::Comma,
@@ -12,10 +12,13 @@ use {
 };
 
 use {
+    crypto::{digest::Digest, sha2::Sha256},
+    flate2::{Compression, write::GzEncoder},
     semver::{Version, Identifier},
     std::{
         env,
-        fs::read_to_string,
+        fs::{read, read_to_string},
+        io::Write,
     },
 };
 
@@ -33,6 +36,44 @@ pub fn template(input: TokenStream) -> TokenStream {
     output.into()
 }
 
+// Expands to an `Asset { etag, identity, gzip, brotli }` struct literal for the given file (whose
+// path is relative to src/, as for template!), so that hashing and compressing big files like
+// the client's wasm happens at build time rather than on every request. The caller has to have
+// a suitable `Asset` type in scope.
+#[proc_macro]
+pub fn embed_asset(input: TokenStream) -> TokenStream {
+    let path_lit = syn::parse_macro_input!(input as LitStr);
+    let path = env::current_dir().unwrap().join("src/").join(path_lit.value());
+    let content = read(&path).unwrap_or_else(|e| panic!("couldn't read {}: {}", path.display(), e));
+
+    let mut hasher = Sha256::new();
+    hasher.input(&content);
+    // Only the first 128 bits, which is plenty to tell builds apart:
+    let etag = format!("\"{}\"", &hasher.result_str()[..32]);
+
+    let mut gz = GzEncoder::new(Vec::new(), Compression::best());
+    gz.write_all(&content).unwrap();
+    let gzip = LitByteStr::new(&gz.finish().unwrap(), path_lit.span());
+
+    let mut br = Vec::new();
+    {
+        let mut writer = brotli::CompressorWriter::new(&mut br, 4096, 11, 22);
+        writer.write_all(&content).unwrap();
+    }
+    let brotli = LitByteStr::new(&br, path_lit.span());
+
+    // Using include_bytes! for the uncompressed content makes cargo rebuild us if it changes:
+    let output = quote! {
+        Asset {
+            etag: #etag,
+            identity: include_bytes!(#path_lit),
+            gzip: #gzip,
+            brotli: #brotli,
+        }
+    };
+    output.into()
+}
+
 #[proc_macro]
 pub fn cargo_pkg_version(_: TokenStream) -> TokenStream {
     let version = Version::parse(&env::var("CARGO_PKG_VERSION").unwrap()).unwrap();
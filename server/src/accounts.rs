@@ -0,0 +1,392 @@
+use {
+    argon2::Variant,
+    hyper::{Body, Method, Request, Response, StatusCode, header},
+    log::error,
+    serde::{Deserialize, Serialize},
+    std::{
+        fmt,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    },
+    tokio::time::Instant,
+};
+
+use crate::{
+    api::read_json,
+    app::RequestCtx,
+    auth::cookie,
+    hyper_helpers::{err_resp, server_header},
+    ratelimit::AddrLimiter,
+    router::{HandlerResult, Router},
+    service::RemoteIp,
+    storage::{AccountStorage, StorageError, StoredLogin},
+};
+
+pub const LOGIN_COOKIE: &str = "concert_login";
+// Tokens can be issued for any user name, so account holders go by their account name with this
+// in front, so that nobody can register their way into being someone with a token.
+pub const ACCOUNT_PREFIX: &str = "~";
+const MAX_USER_LEN: usize = 64;
+const MIN_PASSWORD_LEN: usize = 8;
+// Hashing is deliberately expensive, so we don't want to be hashing novels:
+const MAX_PASSWORD_LEN: usize = 1024;
+
+// Password logins, for people rather than the services that get tokens from auth. Logging in gets
+// you a cookie naming a login we keep track of here, which browsers then send along when they open
+// a websocket. Logins that have expired get cleared out whenever a new one starts.
+pub struct Accounts {
+    storage: Box<dyn AccountStorage>,
+    login_ttl: Duration,
+    // Browsers won't send Secure cookies back over plain HTTP, so we only ask for it with TLS:
+    secure_cookies: bool,
+    // Every attempt costs us a password hash, so nobody gets to make too many:
+    limiter: Option<AddrLimiter>,
+}
+
+impl Accounts {
+    pub fn new(storage: Box<dyn AccountStorage>, login_ttl: Duration, secure_cookies: bool, limiter: Option<AddrLimiter>) -> Self {
+        Accounts { storage, login_ttl, secure_cookies, limiter }
+    }
+
+    // Ok if the request's sender can have another go at a password, otherwise how long until
+    // they can:
+    pub fn check_rate(&self, req: &Request<Body>, now: Instant) -> Result<(), Duration> {
+        match (&self.limiter, req.extensions().get::<RemoteIp>()) {
+            (Some(limiter), Some(RemoteIp(addr))) => limiter.check(*addr, now),
+            _ => Ok(()),
+        }
+    }
+
+    // Registering and logging in both give back the id of the new login. They hash passwords,
+    // which takes a good while on purpose, so are best kept off the async threads.
+    pub fn register(&self, user: &str, password: &str, now: SystemTime) -> Result<String, AccountError> {
+        validate(user, password)?;
+        if !self.storage.create_account(user, &hash_password(password)?)? {
+            return Err(AccountError::Taken);
+        }
+        self.start_login(user, now)
+    }
+
+    pub fn log_in(&self, user: &str, password: &str, now: SystemTime) -> Result<String, AccountError> {
+        if password.len() > MAX_PASSWORD_LEN {
+            return Err(AccountError::BadCredentials);
+        }
+        let ok = match self.storage.password_hash(user)? {
+            Some(hash) => argon2::verify_encoded(&hash, password.as_bytes())?,
+            // Still hash something, so that how long we take doesn't give away who has an account:
+            None => hash_password(password).map(|_| false)?,
+        };
+        if !ok {
+            return Err(AccountError::BadCredentials);
+        }
+        self.start_login(user, now)
+    }
+
+    pub fn log_out(&self, id: &str) -> Result<(), StorageError> {
+        self.storage.destroy_login(id)
+    }
+
+    // Who the request's from, if it carries the cookie for a login that's still current. Looking
+    // that up can mean going to disk, so it's done off the async threads:
+    pub async fn current_user(self: &Arc<Self>, req: &Request<Body>) -> Result<Option<String>, AccountError> {
+        let id = match cookie(req, LOGIN_COOKIE) {
+            Some(id) => id.to_string(),
+            None => return Ok(None),
+        };
+        let accounts = self.clone();
+        blocking(move || Ok(accounts.user(&id, SystemTime::now())?)).await
+    }
+
+    // Who's logged in with the given login, if it's still current:
+    pub fn user(&self, id: &str, now: SystemTime) -> Result<Option<String>, StorageError> {
+        match self.storage.login(id)? {
+            Some(login) if login.expires > now => Ok(Some(login.user)),
+            Some(_) => self.storage.destroy_login(id).map(|()| None),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_cookie(&self, id: &str) -> String {
+        self.cookie(id, self.login_ttl)
+    }
+
+    pub fn clear_cookie(&self) -> String {
+        self.cookie("", Duration::from_secs(0))
+    }
+
+    fn cookie(&self, value: &str, max_age: Duration) -> String {
+        format!(
+            "{}={}; Max-Age={}; Path=/; HttpOnly; SameSite=Strict{}",
+            LOGIN_COOKIE, value, max_age.as_secs(), if self.secure_cookies { "; Secure" } else { "" }
+        )
+    }
+
+    fn start_login(&self, user: &str, now: SystemTime) -> Result<String, AccountError> {
+        let id = base64::encode_config(rand::random::<[u8; 32]>(), base64::URL_SAFE_NO_PAD);
+        self.storage.prune_logins(now)?;
+        self.storage.create_login(&id, &StoredLogin { user: user.to_string(), expires: now + self.login_ttl })?;
+        Ok(id)
+    }
+}
+
+// Who someone logged in to the given account is, as far as everything else is concerned:
+pub fn account_user(account: &str) -> String {
+    format!("{}{}", ACCOUNT_PREFIX, account)
+}
+
+pub fn is_account_user(user: &str) -> bool {
+    user.starts_with(ACCOUNT_PREFIX)
+}
+
+fn validate(user: &str, password: &str) -> Result<(), AccountError> {
+    if user.is_empty() || user.chars().count() > MAX_USER_LEN {
+        return Err(AccountError::Invalid(format!("User names must be 1 to {} characters", MAX_USER_LEN)));
+    }
+    if user.trim() != user || user.chars().any(char::is_control) {
+        return Err(AccountError::Invalid("User names can't have surrounding space or control characters".to_string()));
+    }
+    if password.chars().count() < MIN_PASSWORD_LEN || password.len() > MAX_PASSWORD_LEN {
+        return Err(AccountError::Invalid(format!(
+            "Passwords must be at least {} characters and at most {} bytes", MIN_PASSWORD_LEN, MAX_PASSWORD_LEN
+        )));
+    }
+    Ok(())
+}
+
+fn hash_password(password: &str) -> Result<String, AccountError> {
+    // OWASP's recommended minimum settings for Argon2id:
+    let config = argon2::Config { variant: Variant::Argon2id, mem_cost: 19 * 1024, time_cost: 2, ..Default::default() };
+    Ok(argon2::hash_encoded(password.as_bytes(), &rand::random::<[u8; 16]>(), &config)?)
+}
+
+pub fn add_routes(router: &mut Router<RequestCtx>) {
+    router.add(&[Method::POST], "/account/register", |req, ctx: RequestCtx| async move {
+        if !ctx.config.open_registration {
+            return err_resp(StatusCode::FORBIDDEN, "Registration is closed".to_string());
+        }
+        if let Err(wait) = ctx.accounts.check_rate(&req, Instant::now()) {
+            return too_many_attempts(wait);
+        }
+        let Credentials { user, password } = match read_json(req, ctx.config.limits.max_message_size).await {
+            Ok(credentials) => credentials,
+            Err(resp) => return resp,
+        };
+        let accounts = ctx.accounts.clone();
+        let result = blocking(move || {
+            accounts.register(&user, &password, SystemTime::now()).map(|id| (user, id))
+        }).await;
+        logged_in(&ctx, StatusCode::CREATED, result)
+    });
+    router.add(&[Method::POST], "/account/login", |req, ctx: RequestCtx| async move {
+        if let Err(wait) = ctx.accounts.check_rate(&req, Instant::now()) {
+            return too_many_attempts(wait);
+        }
+        let Credentials { user, password } = match read_json(req, ctx.config.limits.max_message_size).await {
+            Ok(credentials) => credentials,
+            Err(resp) => return resp,
+        };
+        let accounts = ctx.accounts.clone();
+        let result = blocking(move || {
+            accounts.log_in(&user, &password, SystemTime::now()).map(|id| (user, id))
+        }).await;
+        logged_in(&ctx, StatusCode::OK, result)
+    });
+    router.add(&[Method::POST], "/account/logout", |req, ctx: RequestCtx| async move {
+        if let Some(id) = cookie(&req, LOGIN_COOKIE).map(str::to_string) {
+            let accounts = ctx.accounts.clone();
+            if let Err(e) = blocking(move || Ok(accounts.log_out(&id)?)).await {
+                return error_resp(&e);
+            }
+        }
+        Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(header::SERVER, server_header())
+            .header(header::SET_COOKIE, ctx.accounts.clear_cookie())
+            .body(Body::empty())
+    });
+    router.get("/account", |req, ctx: RequestCtx| async move {
+        match ctx.accounts.current_user(&req).await {
+            Ok(Some(user)) => account_resp(StatusCode::OK, user, None),
+            Ok(None) => err_resp(StatusCode::UNAUTHORIZED, "Not logged in".to_string()),
+            Err(e) => error_resp(&e),
+        }
+    });
+}
+
+#[derive(Deserialize)]
+struct Credentials {
+    user: String,
+    password: String,
+}
+
+#[derive(Serialize)]
+struct Account {
+    user: String,
+}
+
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, AccountError> + Send + 'static
+) -> Result<T, AccountError> {
+    tokio::task::spawn_blocking(f).await.unwrap_or_else(|e| Err(AccountError::Internal(e.to_string())))
+}
+
+fn logged_in(ctx: &RequestCtx, status: StatusCode, result: Result<(String, String), AccountError>) -> HandlerResult {
+    match result {
+        Ok((user, id)) => account_resp(status, user, Some(ctx.accounts.set_cookie(&id))),
+        Err(e) => error_resp(&e),
+    }
+}
+
+fn account_resp(status: StatusCode, user: String, set_cookie: Option<String>) -> HandlerResult {
+    let mut resp = Response::builder()
+        .status(status)
+        .header(header::SERVER, server_header())
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CACHE_CONTROL, "no-store");
+    if let Some(cookie) = set_cookie {
+        resp = resp.header(header::SET_COOKIE, cookie);
+    }
+    resp.body(Body::from(serde_json::to_vec(&Account { user }).expect("unserialisable")))
+}
+
+fn too_many_attempts(wait: Duration) -> HandlerResult {
+    let secs = wait.as_secs() + (wait.subsec_nanos() > 0) as u64;
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header(header::SERVER, server_header())
+        .header(header::RETRY_AFTER, secs)
+        .body(Body::from("Too many attempts, try again later"))
+}
+
+fn error_resp(e: &AccountError) -> HandlerResult {
+    let status = match e {
+        AccountError::Invalid(_) => StatusCode::BAD_REQUEST,
+        AccountError::Taken => StatusCode::CONFLICT,
+        AccountError::BadCredentials => StatusCode::UNAUTHORIZED,
+        AccountError::Internal(_) => {
+            error!("Account error: {}", e);
+            return err_resp(StatusCode::INTERNAL_SERVER_ERROR, "Internal error".to_string());
+        },
+    };
+    err_resp(status, e.to_string())
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AccountError {
+    Invalid(String),
+    Taken,
+    BadCredentials,
+    // Our problem rather than the user's:
+    Internal(String),
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AccountError::Invalid(reason) => f.write_str(reason),
+            AccountError::Taken => f.write_str("That user name is taken"),
+            AccountError::BadCredentials => f.write_str("Wrong user name or password"),
+            AccountError::Internal(e) => f.write_str(e),
+        }
+    }
+}
+
+impl std::error::Error for AccountError {}
+
+impl From<StorageError> for AccountError {
+    fn from(e: StorageError) -> Self {
+        AccountError::Internal(e.to_string())
+    }
+}
+
+impl From<argon2::Error> for AccountError {
+    fn from(e: argon2::Error) -> Self {
+        AccountError::Internal(format!("password hashing failed: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{metrics::Metrics, storage::MemoryAccounts},
+    };
+
+    fn with_cookie(cookie: &str) -> Request<Body> {
+        // Browsers only send back the name and value:
+        let cookie = cookie.split(';').next().unwrap();
+        Request::builder().header(header::COOKIE, cookie).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_accounts() {
+        let accounts = Arc::new(Accounts::new(Box::new(MemoryAccounts::default()), Duration::from_secs(60), false, None));
+        let now = SystemTime::now();
+        let id = accounts.register("paul", "correct horse", now).unwrap();
+        assert_eq!(accounts.register("paul", "battery staple", now), Err(AccountError::Taken));
+        assert_eq!(accounts.log_in("paul", "battery staple", now), Err(AccountError::BadCredentials));
+        assert_eq!(accounts.log_in("nobody", "correct horse", now), Err(AccountError::BadCredentials));
+        let other_id = accounts.log_in("paul", "correct horse", now).unwrap();
+        assert_ne!(id, other_id);
+
+        assert_eq!(accounts.user(&id, now).unwrap(), Some("paul".to_string()));
+        assert_eq!(accounts.user(&id, now + Duration::from_secs(60)).unwrap(), None);
+        // Expired logins stay expired:
+        assert_eq!(accounts.user(&id, now).unwrap(), None);
+        // And get cleared out when someone next logs in, even if nobody tries them again:
+        let later = now + Duration::from_secs(120);
+        let id = accounts.log_in("paul", "correct horse", later).unwrap();
+        assert_eq!(accounts.user(&other_id, now).unwrap(), None);
+
+        let req = with_cookie(&accounts.set_cookie(&id));
+        assert_eq!(accounts.current_user(&req).await.unwrap(), Some("paul".to_string()));
+        accounts.log_out(&id).unwrap();
+        assert_eq!(accounts.current_user(&req).await.unwrap(), None);
+        assert_eq!(accounts.current_user(&with_cookie("")).await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_check_rate() {
+        let limiter = AddrLimiter::new(1, "login", Arc::new(Metrics::new()));
+        let accounts = Accounts::new(Box::new(MemoryAccounts::default()), Duration::from_secs(60), false, limiter);
+        let start = Instant::now();
+        let from = |ip: &str| {
+            let mut req = Request::new(Body::empty());
+            req.extensions_mut().insert(RemoteIp(ip.parse().unwrap()));
+            req
+        };
+        assert_eq!(accounts.check_rate(&from("10.0.0.1"), start), Ok(()));
+        assert_eq!(accounts.check_rate(&from("10.0.0.1"), start), Err(Duration::from_secs(60)));
+        assert_eq!(accounts.check_rate(&from("10.0.0.2"), start), Ok(()));
+        // Without knowing where a request's from, there's nothing to go on:
+        assert_eq!(accounts.check_rate(&Request::new(Body::empty()), start), Ok(()));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate("paul", "correct horse").is_ok());
+        assert!(validate("", "correct horse").is_err());
+        assert!(validate(" paul", "correct horse").is_err());
+        assert!(validate("pa\nul", "correct horse").is_err());
+        assert!(validate(&"p".repeat(MAX_USER_LEN + 1), "correct horse").is_err());
+        assert!(validate("paul", "short").is_err());
+        assert!(validate("paul", &"p".repeat(MAX_PASSWORD_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn test_cookie() {
+        let accounts = Accounts::new(Box::new(MemoryAccounts::default()), Duration::from_secs(60), true, None);
+        assert_eq!(
+            accounts.set_cookie("abc"),
+            "concert_login=abc; Max-Age=60; Path=/; HttpOnly; SameSite=Strict; Secure"
+        );
+        assert!(accounts.clear_cookie().starts_with("concert_login=; Max-Age=0;"));
+    }
+
+    #[test]
+    fn test_account_user() {
+        assert_eq!(account_user("paul"), "~paul");
+        assert!(is_account_user(&account_user("paul")));
+        assert!(!is_account_user("paul"));
+    }
+}
@@ -0,0 +1,195 @@
+use {
+    crypto::util::fixed_time_eq,
+    futures::future::{FutureExt, ready},
+    hyper::{
+        Body,
+        Method,
+        Request,
+        Response,
+        StatusCode,
+        body::{Buf, HttpBody},
+        header,
+    },
+    serde::{Deserialize, Serialize, de::DeserializeOwned},
+    std::sync::Arc,
+};
+
+use {
+    common::clapi::{ClientId, ProtocolError, Recipient, RoomName},
+    crate::{
+        app::{ask, AppCmd, RequestCtx},
+        hyper_helpers::{err_resp, server_header},
+        router::{Handler, HandlerResult, Params, Router},
+    },
+};
+
+// These can kick people and speak for the server, so they're only for whoever has the admin
+// token, and are turned off altogether unless there is one.
+pub fn add_routes(router: &mut Router<RequestCtx>) {
+    router.get("/api/clients", |_, ctx: RequestCtx| async move {
+        match ask(&ctx.tx, AppCmd::ListClients).await {
+            Some(clients) => json_resp(StatusCode::OK, &clients),
+            None => unavailable(),
+        }
+    }).wrap(admin_only);
+    router.add(&[Method::DELETE], "/api/clients/:id", |req, ctx: RequestCtx| async move {
+        let client_id: ClientId = match Params::get(&req, "id").and_then(|id| id.parse().ok()) {
+            Some(id) => id,
+            None => return err_resp(StatusCode::NOT_FOUND, "".to_string()),
+        };
+        match ask(&ctx.tx, |reply| AppCmd::Kick(client_id, reply)).await {
+            Some(result) => done(result),
+            None => unavailable(),
+        }
+    }).wrap(admin_only);
+    router.get("/api/rooms", |_, ctx: RequestCtx| async move {
+        match ask(&ctx.tx, AppCmd::ListRooms).await {
+            Some(rooms) => json_resp(StatusCode::OK, &rooms),
+            None => unavailable(),
+        }
+    }).wrap(admin_only);
+    router.add(&[Method::POST], "/api/broadcast", |req, ctx: RequestCtx| async move {
+        let limit = ctx.config.limits.max_message_size;
+        let Announcement { room, text } = match read_json(req, limit).await {
+            Ok(a) => a,
+            Err(resp) => return resp,
+        };
+        match ask(&ctx.tx, |reply| AppCmd::Announce { room, text, reply }).await {
+            Some(result) => done(result),
+            None => unavailable(),
+        }
+    }).wrap(admin_only);
+}
+
+// The token has to come in an Authorization header, which browsers won't add to requests that
+// other sites' pages make, so nobody can be tricked into using the API on our behalf.
+fn admin_only(inner: Handler<RequestCtx>) -> Handler<RequestCtx> {
+    Arc::new(move |req, ctx: RequestCtx| {
+        let token = match &ctx.config.admin_token {
+            Some(token) => token,
+            None => return ready(err_resp(StatusCode::FORBIDDEN, "The API is turned off".to_string())).boxed(),
+        };
+        if !is_admin(&req, &token.0) {
+            return ready(
+                Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header(header::SERVER, server_header())
+                    .header(header::WWW_AUTHENTICATE, "Bearer")
+                    .body(Body::from("Missing or wrong admin token"))
+            ).boxed();
+        }
+        inner(req, ctx)
+    })
+}
+
+fn is_admin(req: &Request<Body>, token: &[u8]) -> bool {
+    let given = req.headers().get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match given {
+        // Same length or not, compared without giving away how much of it matched:
+        Some(given) => fixed_time_eq(given.trim().as_bytes(), token),
+        None => false,
+    }
+}
+
+// Goes to everyone if no room is given:
+#[derive(Deserialize)]
+struct Announcement {
+    room: Option<RoomName>,
+    text: String,
+}
+
+// Reads a JSON request body, giving up on any that are bigger than we'd accept over a websocket.
+// Insisting on the JSON content type means that plain HTML forms on other sites can't post to us.
+pub(crate) async fn read_json<T: DeserializeOwned>(req: Request<Body>, limit: usize) -> Result<T, HandlerResult> {
+    let is_json = req.headers().get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("application/json"));
+    if !is_json {
+        return Err(err_resp(StatusCode::UNSUPPORTED_MEDIA_TYPE, "Expected application/json".to_string()));
+    }
+    let mut body = req.into_body();
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| error_resp(&ProtocolError::Malformed { reason: e.to_string() }))?;
+        if buf.len() + chunk.remaining() > limit {
+            return Err(err_resp(StatusCode::PAYLOAD_TOO_LARGE, format!("Body exceeds {} bytes", limit)));
+        }
+        buf.extend_from_slice(chunk.chunk());
+    }
+    serde_json::from_slice(&buf)
+        .map_err(|e| error_resp(&ProtocolError::Malformed { reason: e.to_string() }))
+}
+
+fn done(result: Result<(), ProtocolError>) -> HandlerResult {
+    match result {
+        Ok(()) => Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(header::SERVER, server_header())
+            .body(Body::empty()),
+        Err(e) => error_resp(&e),
+    }
+}
+
+// API errors have the same shape as the ones we send over websockets:
+fn error_resp(e: &ProtocolError) -> HandlerResult {
+    let status = match e {
+        ProtocolError::Malformed { .. } => StatusCode::BAD_REQUEST,
+        ProtocolError::NoSuchRoom { .. } => StatusCode::NOT_FOUND,
+        ProtocolError::RecipientNotFound { recipient: Recipient::Id(_) } => StatusCode::NOT_FOUND,
+        _ => StatusCode::CONFLICT,
+    };
+    json_resp(status, e)
+}
+
+fn unavailable() -> HandlerResult {
+    err_resp(StatusCode::SERVICE_UNAVAILABLE, "Shutting down".to_string())
+}
+
+fn json_resp<T: Serialize + ?Sized>(status: StatusCode, value: &T) -> HandlerResult {
+    Response::builder()
+        .status(status)
+        .header(header::SERVER, server_header())
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(value).expect("unserialisable")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_json() {
+        let req = |body: &'static str| Request::builder()
+            .header(header::CONTENT_TYPE, "application/json; charset=utf-8")
+            .body(Body::from(body))
+            .unwrap();
+        let a: Announcement = read_json(req(r#"{"text": "hi"}"#), 100).await.ok().unwrap();
+        assert_eq!((a.room, a.text.as_str()), (None, "hi"));
+        let resp = read_json::<Announcement>(req(r#"{"room": "lobby"}"#), 100).await.err().unwrap();
+        assert_eq!(resp.unwrap().status(), StatusCode::BAD_REQUEST);
+        let resp = read_json::<Announcement>(req(r#"{"text": "too long"}"#), 10).await.err().unwrap();
+        assert_eq!(resp.unwrap().status(), StatusCode::PAYLOAD_TOO_LARGE);
+        for content_type in [None, Some("text/plain"), Some("application/x-www-form-urlencoded")].iter() {
+            let mut req = Request::builder();
+            if let Some(content_type) = content_type {
+                req = req.header(header::CONTENT_TYPE, *content_type);
+            }
+            let resp = read_json::<Announcement>(req.body(Body::from(r#"{"text": "hi"}"#)).unwrap(), 100).await;
+            assert_eq!(resp.err().unwrap().unwrap().status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        }
+    }
+
+    #[test]
+    fn test_is_admin() {
+        let token = b"0123456789abcdef";
+        let req = |auth: &str| Request::builder().header(header::AUTHORIZATION, auth).body(Body::empty()).unwrap();
+        assert!(is_admin(&req("Bearer 0123456789abcdef"), token));
+        assert!(!is_admin(&req("Bearer 0123456789abcdeg"), token));
+        assert!(!is_admin(&req("Bearer 0123456789"), token));
+        assert!(!is_admin(&req("Basic 0123456789abcdef"), token));
+        assert!(!is_admin(&Request::new(Body::empty()), token));
+    }
+}
@@ -2,7 +2,7 @@ use {
     futures::{
         stream, StreamExt, SinkExt,
         FutureExt,
-        future::{join_all, Either::{Left, Right}},
+        future::{join_all, ready, try_join_all, Either::{Left, Right}},
         channel::mpsc, channel::oneshot,
     },
     hyper::{
@@ -11,126 +11,292 @@ use {
         Response,
         Server,
         StatusCode,
-        header, header::HeaderValue,
+        header,
         http,
+        server::accept,
     },
-    log::{info, error, warn},
+    log::{debug, info, error, warn},
     std::{
-        collections::BTreeMap,
-        net::SocketAddr,
+        collections::{BTreeMap, VecDeque},
+        error::Error,
+        path::Path,
+        sync::{Arc, atomic::{AtomicBool, Ordering}},
+        time::{Duration, SystemTime},
     },
-    tokio::signal::unix::Signal,
+    tokio::{io::{AsyncRead, AsyncWrite}, net::TcpListener, signal::unix::Signal, time::{interval, Instant}},
     tokio_tungstenite::{
-        tungstenite::protocol::{Role, Message},
+        tungstenite::protocol::{
+            Role, Message, CloseFrame, WebSocketConfig,
+            frame::coding::CloseCode,
+        },
         WebSocketStream,
     },
 };
 
 use {
-    common,
+    common::{
+        self,
+        clapi::{
+            ClientId, ClientInfo, ClientMsg, RoomInfo, RoomName, ServerMsg, ClientToServer, ServerToClient, Codec,
+            Encoding, DecodeError, Grantee, Protocol, ProtocolError, Recipient, Role as RoomRole, SessionToken,
+        },
+    },
     crate::{
-        hyper_helpers::{hv, unhv, mk_accept_header, server_header, err_resp},
+        accounts::{self, Accounts},
+        api,
+        auth,
+        config::Config,
+        health,
+        metrics::{count_hits, metrics_resp, time_requests, Metrics},
+        ratelimit::{AddrLimiter, ClientLimiter, Verdict},
+        hyper_helpers::{hv, unhv, header_list, mk_accept_header, query_param, server_header, err_resp},
         resources,
+        rooms::{Actor, Rooms},
+        storage::{self, StorageError},
+        router::{Handler, Router},
         service::ConnectionHandler,
+        tls,
+        watch::watch_paths,
     },
 };
 
+const DEV_POLL_INTERVAL: Duration = Duration::from_millis(500);
+// The most we'll keep for a disconnected client. Any more than that and it's better off starting
+// afresh than trying to catch up:
+const MAX_MISSED_MESSAGES: usize = 1024;
+
 pub struct App {
-    sigint: Option<Signal>,
+    config: Arc<Config>,
     shutting_down: bool,
-    clients: BTreeMap<u16, Client>,
-    next_client_id: u16,
+    // Shared with request handlers, so that they can check it without bothering app_main:
+    ready: Arc<AtomicBool>,
+    metrics: Arc<Metrics>,
+    // Only used by request handlers:
+    accounts: Arc<Accounts>,
+    clients: BTreeMap<ClientId, Client>,
+    next_client_id: ClientId,
+    rooms: Rooms,
 }
 
 impl App {
-    pub fn new(sigint: Signal) -> Self {
-        App {
-            sigint: Some(sigint),
+    pub fn new(config: Config) -> Result<Self, StorageError> {
+        let (storage, account_storage) = storage::open(config.data_dir.as_deref())?;
+        let rooms = Rooms::load(config.history.clone(), storage)?;
+        let metrics = Arc::new(Metrics::new());
+        let login_limiter = AddrLimiter::new(config.limits.logins_per_min, "login", metrics.clone());
+        let accounts = Accounts::new(account_storage, config.login_ttl, config.tls.is_some(), login_limiter);
+        Ok(App {
+            config: Arc::new(config),
             shutting_down: false,
+            ready: Arc::new(AtomicBool::new(true)),
+            metrics,
+            accounts: Arc::new(accounts),
             clients: BTreeMap::new(),
             next_client_id: 0,
-        }
+            rooms,
+        })
     }
 
-    pub async fn serve(mut self, addr: &SocketAddr) -> Result<(), hyper::Error> {
-        // We have to be able to take the Signal out of self so that we can pass self.app_main to
-        // spawn...
-        let (graceful_rx, app_main_shutdown_rx) = Self::watch_sigint(self.sigint.take().unwrap());
-        let (conn_handler, cmd_rx) = ConnectionHandler::new(&handle_request);
+    pub async fn serve(self, sigint: Signal) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let app_main_shutdown_rx = Self::watch_sigint(sigint);
+        let config = self.config.clone();
+        let ready = self.ready.clone();
+        let metrics = self.metrics.clone();
+        let accounts = self.accounts.clone();
+        let router = routes(metrics.clone());
+        let limiter = AddrLimiter::new(config.limits.connections_per_min, "connection", metrics.clone());
+        let (conn_handler, cmd_rx) = ConnectionHandler::new(move |req, tx| router.handle(req, RequestCtx {
+            tx, config: config.clone(), ready: ready.clone(), metrics: metrics.clone(), accounts: accounts.clone()
+        }), limiter);
+        if let (true, Some(dir)) = (self.config.dev, &self.config.static_dir) {
+            watch_client_assets(dir, conn_handler.sender());
+        }
+        let tls_acceptor = self.config.tls.as_ref()
+            .map(|tls| tls::acceptor(tls).map(|acceptor| (acceptor, tls.handshake_timeout)))
+            .transpose()?;
+        let config = self.config.clone();
+        // We keep serving HTTP while clients drain away after the first SIGINT, so that load
+        // balancers can see we're no longer ready, and only stop once the app has finished:
         let app_main_handle = tokio::task::spawn(self.app_main(cmd_rx, app_main_shutdown_rx));
-        let server_builder = Server::bind(addr);
-        info!("Visit http://{}/index.html to start", &addr);
-        let server = server_builder
-            .serve(conn_handler)
-            .with_graceful_shutdown(graceful_rx.map(|r| r.expect("cancelled instead")));
-        // If we just do this one next on the stream, there's no point in wrapping the
-        // Signal::recv()...
-        server.await?;
-        Ok(app_main_handle.await.expect("join error"))
-    }
-
-    fn watch_sigint(mut sigint: Signal) -> (oneshot::Receiver<()>, mpsc::Receiver<AppShutdown>) {
-        let (graceful_tx, graceful_rx) = oneshot::channel();
+        let graceful = app_main_handle.map(|r| r.expect("join error")).shared();
+        let mut servers = Vec::new();
+        for addr in config.listen.iter() {
+            match &tls_acceptor {
+                None => {
+                    let server_builder = Server::try_bind(addr)?;
+                    info!("Visit http://{}/index.html to start", &addr);
+                    servers.push(server_builder
+                        .serve(conn_handler.clone())
+                        .with_graceful_shutdown(graceful.clone())
+                        .boxed());
+                },
+                Some((acceptor, handshake_timeout)) => {
+                    let listener = TcpListener::bind(addr).await?;
+                    let incoming = tls::incoming(listener, acceptor.clone(), *handshake_timeout);
+                    info!("Visit https://{}/index.html to start", &addr);
+                    servers.push(Server::builder(accept::from_stream(incoming))
+                        .serve(conn_handler.clone())
+                        .with_graceful_shutdown(graceful.clone())
+                        .boxed());
+                }
+            }
+        }
+        try_join_all(servers).await?;
+        graceful.await;
+        Ok(())
+    }
+
+    fn watch_sigint(mut sigint: Signal) -> mpsc::Receiver<AppShutdown> {
         let (mut app_main_tx, app_main_rx) = mpsc::channel(2);
         tokio::task::spawn(async move {
             sigint.recv().await;
             app_main_tx.send(AppShutdown::Soft).await.expect("bad send");
-            graceful_tx.send(()).expect("bad graceful send");
             sigint.recv().await;
-            app_main_tx.send(AppShutdown::Hard).await.expect("bad send");
+            // app_main might have finished of its own accord by now:
+            let _ = app_main_tx.send(AppShutdown::Hard).await;
         });
-        (graceful_rx, app_main_rx)
+        app_main_rx
     }
 
     async fn app_main(mut self, rx: mpsc::UnboundedReceiver<AppCmd>, shutdown_rx: mpsc::Receiver<AppShutdown>) {
+        let heartbeats = stream::unfold(interval(self.config.heartbeat.interval), |mut ticks| async {
+            ticks.tick().await;
+            Some((AppCmd::Heartbeat, ticks))
+        // The first tick is immediate, and nobody's connected yet to need a ping:
+        }).skip(1).boxed();
         let mut both = stream::select(
-            shutdown_rx.map(|x| Left(x)),
-            rx.map(|x| Right(x))
+            shutdown_rx.map(Left),
+            stream::select(rx, heartbeats).map(Right)
         );
         loop {
             match both.next().await {
                 Some(Left(shutdown)) => match shutdown {
                     AppShutdown::Soft => {
-                        if self.clients.len() == 0 {
+                        // Nobody's coming back to a server that's going away:
+                        let detached: Vec<ClientId> = self.clients.iter()
+                            .filter(|(_, c)| !c.is_attached())
+                            .map(|(id, _)| *id)
+                            .collect();
+                        for client_id in detached {
+                            self.remove_client(client_id).await;
+                        }
+                        if self.clients.is_empty() {
                             warn!("SIGINT - no clients, bye!");
                             break
                         } else {
                             warn!("SIGINT - waiting for clients to disconnect. Interrupt again to force-quit");
                             self.shutting_down = true;
+                            self.ready.store(false, Ordering::Relaxed);
                         }
                     },
                     AppShutdown::Hard => {
                         warn!("Hard app shutdown, closing remaining connections...");
-                        self.send_all(Message::Close(None)).await;
+                        self.send_all_raw(Message::Close(None)).await;
                         break
                     }
                 },
                 Some(Right(cmd)) => match cmd {
-                    AppCmd::NewClient(mut client_tx) => {
-                        let id = self.next_client_id;
-                        info!("new client ({}) connected!", id);
-                        client_tx.send(ClientEvent::ClientId(id)).await;
-                        let result = self.clients.insert(id, Client { tx: client_tx });
-                        // FIXME: replace with Option::expect_none() when in stable:
-                        if let Some(_client) = result { panic!("client ID already in map") }
-                        self.next_client_id += 1;
+                    AppCmd::NewClient(mut client_tx, _) if self.shutting_down => {
+                        let _ = client_tx.send(ClientEvent::AppMsg(Message::Close(Some(CloseFrame {
+                            code: CloseCode::Again,
+                            reason: "Shutting down".into(),
+                        })))).await;
                     },
-                    AppCmd::ClientMsg(client_id, msg) => match msg {
-                        Message::Binary(b) => todo!(),
-                        Message::Text(s) => {
-                            info!("Server received text {:?}", s);
-                            self.send_all(Message::Text(s.clone())).await;
+                    AppCmd::NewClient(mut client_tx, handshake) if !self.has_room(&handshake) => {
+                        warn!("Turning away new client, we're full");
+                        let _ = client_tx.send(ClientEvent::AppMsg(Message::Close(Some(CloseFrame {
+                            code: CloseCode::Again,
+                            reason: "Server full".into(),
+                        })))).await;
+                    },
+                    AppCmd::NewClient(client_tx, handshake) => {
+                        match self.find_session(&handshake) {
+                            Some(id) => self.resume_client(id, client_tx, handshake.protocol).await,
+                            None => self.add_client(client_tx, handshake).await,
                         }
+                    },
+                    AppCmd::HasRoom(handshake, reply) => {
+                        let _ = reply.send(self.has_room(&handshake));
+                    },
+                    AppCmd::Heartbeat => {
+                        self.heartbeat().await;
+                        if self.shutting_down && self.clients.is_empty() {
+                            info!("Last client left, bye!");
+                            break
+                        }
+                    },
+                    AppCmd::AssetsChanged => {
+                        self.send_all(&ServerMsg::event(ServerToClient::Reload)).await;
+                    },
+                    AppCmd::ListClients(reply) => {
+                        let _ = reply.send(self.clients.iter().map(|(id, c)| c.info(*id)).collect());
+                    },
+                    AppCmd::ListRooms(reply) => {
+                        let _ = reply.send(self.rooms.info());
+                    },
+                    AppCmd::Announce { room, text, reply } => {
+                        let msg = ServerMsg::event(ServerToClient::Announcement { room: room.clone(), text });
+                        let result = match room {
+                            None => {
+                                self.send_all(&msg).await;
+                                Ok(())
+                            },
+                            Some(room) if self.rooms.get(&room).is_some() => {
+                                self.send_room(&room, &msg).await;
+                                Ok(())
+                            },
+                            Some(room) => Err(ProtocolError::NoSuchRoom { room }),
+                        };
+                        let _ = reply.send(result);
+                    },
+                    AppCmd::Kick(client_id, reply) => {
+                        match self.clients.get_mut(&client_id) {
+                            Some(client) => {
+                                warn!("Kicking client {}", client_id);
+                                client.send_raw(Message::Close(Some(CloseFrame {
+                                    code: CloseCode::Policy,
+                                    reason: "Kicked".into(),
+                                }))).await;
+                                self.remove_client(client_id).await;
+                                let _ = reply.send(Ok(()));
+                            },
+                            None => {
+                                let _ = reply.send(Err(ProtocolError::RecipientNotFound {
+                                    recipient: Recipient::Id(client_id)
+                                }));
+                            },
+                        }
+                        if self.shutting_down && self.clients.is_empty() {
+                            info!("Last client left, bye!");
+                            break
+                        }
+                    },
+                    AppCmd::ClientGone(client_id, client_tx) => {
+                        // Unless it's an old connection that the client's since replaced:
+                        if self.clients.get(&client_id).is_some_and(|c| c.is_connected_to(&client_tx)) {
+                            warn!("client {} went away without saying goodbye", client_id);
+                            self.detach_client(client_id).await;
+                            if self.shutting_down && self.clients.is_empty() {
+                                info!("Last client left, bye!");
+                                break
+                            }
+                        }
+                    },
+                    // Anything still in flight from a client we've since kicked or lost:
+                    AppCmd::ClientMsg(client_id, _) if !self.clients.get(&client_id).is_some_and(Client::is_attached) => (),
+                    AppCmd::ClientMsg(client_id, msg) => match msg {
+                        Message::Binary(b) => self.handle_frame(client_id, ClientMsg::from_binary(&b)).await,
+                        Message::Text(s) => self.handle_frame(client_id, ClientMsg::from_text(&s)).await,
                         Message::Ping(b) => {
-                            self.clients.get_mut(&client_id).expect("no client?").tx.send(
-                                ClientEvent::AppMsg(Message::Pong(b))
-                            ).await;
+                            self.client_mut(client_id).send_raw(Message::Pong(b)).await;
+                        },
+                        Message::Pong(_) => {
+                            self.client_mut(client_id).missed_pongs = 0;
                         },
-                        Message::Pong(b) => todo!(),
                         Message::Close(b) => {
                             warn!("client {} disconnected with {:?}", client_id, b);
-                            self.clients.remove(&client_id).expect("no client in map");
-                            if self.shutting_down && self.clients.len() == 0 {
+                            self.remove_client(client_id).await;
+                            if self.shutting_down && self.clients.is_empty() {
                                 info!("Last client left, bye!");
                                 break
                             }
@@ -140,95 +306,602 @@ impl App {
                 None => break
             }
         }
+        self.rooms.flush();
+    }
+
+    // Pings everyone, and disconnects anyone who hasn't answered enough of the previous pings,
+    // whose connections have presumably died without telling us. Also where we finally give up on
+    // disconnected clients that haven't come back in time.
+    async fn heartbeat(&mut self) {
+        let grace = self.config.session_grace;
+        let expired: Vec<ClientId> = self.clients.iter()
+            .filter(|(_, c)| match &c.conn {
+                Conn::Attached(_) => false,
+                Conn::Detached { since, overflowed, .. } => *overflowed || since.elapsed() >= grace,
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for client_id in expired {
+            info!("Client {} didn't come back, forgetting about it", client_id);
+            self.remove_client(client_id).await;
+        }
+
+        let max_missed = self.config.heartbeat.max_missed;
+        let dead: Vec<ClientId> = self.clients.iter()
+            .filter(|(_, c)| c.is_attached() && c.missed_pongs >= max_missed)
+            .map(|(id, _)| *id)
+            .collect();
+        for client_id in dead {
+            warn!("Client {} missed {} heartbeats, disconnecting", client_id, max_missed);
+            self.client_mut(client_id).send_raw(Message::Close(Some(CloseFrame {
+                code: CloseCode::Policy,
+                reason: "Missed heartbeats".into(),
+            }))).await;
+            self.detach_client(client_id).await;
+            self.metrics.heartbeat_evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        for client in self.clients.values_mut().filter(|c| c.is_attached()) {
+            client.missed_pongs += 1;
+            client.send_raw(Message::Ping(Vec::new())).await;
+        }
+    }
+
+    // Resuming a session takes up no more room than the client was already using:
+    fn has_room(&self, handshake: &Handshake) -> bool {
+        self.clients.len() < self.config.limits.max_clients || self.find_session(handshake).is_some()
+    }
+
+    // Sessions can only be picked up again by whoever they belonged to in the first place:
+    fn find_session(&self, handshake: &Handshake) -> Option<ClientId> {
+        let session = handshake.session.as_ref()?;
+        self.clients.iter()
+            .find(|(_, c)| &c.session == session && c.user == handshake.user && c.can_resume())
+            .map(|(id, _)| *id)
+    }
+
+    async fn add_client(&mut self, mut client_tx: mpsc::UnboundedSender<ClientEvent>, handshake: Handshake) {
+        let id = self.next_client_id;
+        let Handshake { protocol, user, .. } = handshake;
+        info!("new client ({}) connected as {}, speaking {}!", id, user.as_deref().unwrap_or("anonymous"), protocol);
+        let _ = client_tx.send(ClientEvent::ClientId(id)).await;
+        let client = Client {
+            conn: Conn::Attached(client_tx),
+            protocol,
+            user,
+            name: None,
+            metadata: BTreeMap::new(),
+            missed_pongs: 0,
+            session: new_session_token(),
+        };
+        self.send_all(&ServerMsg::event(ServerToClient::ClientJoined { client: client.info(id) })).await;
+        let result = self.clients.insert(id, client);
+        // FIXME: replace with Option::expect_none() when in stable:
+        if let Some(_client) = result { panic!("client ID already in map") }
+        self.update_connected();
+        self.next_client_id += 1;
+        self.welcome(id, false).await;
+    }
+
+    // Hands a client its old id back, along with everything it missed while it was gone. Nobody
+    // else need know it was ever away.
+    async fn resume_client(&mut self, client_id: ClientId, mut client_tx: mpsc::UnboundedSender<ClientEvent>, protocol: Protocol) {
+        let _ = client_tx.send(ClientEvent::ClientId(client_id)).await;
+        let client = self.client_mut(client_id);
+        let missed = match std::mem::replace(&mut client.conn, Conn::Attached(client_tx)) {
+            Conn::Detached { missed, .. } => missed,
+            // The old connection must be dead, we just haven't noticed yet:
+            Conn::Attached(mut old_tx) => {
+                let _ = old_tx.send(ClientEvent::AppMsg(Message::Close(Some(CloseFrame {
+                    code: CloseCode::Policy,
+                    reason: "Session resumed elsewhere".into(),
+                })))).await;
+                VecDeque::new()
+            },
+        };
+        client.protocol = protocol;
+        client.missed_pongs = 0;
+        client.session = new_session_token();
+        info!("client {} resumed its session, speaking {}, {} missed messages", client_id, protocol, missed.len());
+        self.update_connected();
+        self.welcome(client_id, true).await;
+        for msg in missed {
+            self.send_to(client_id, &msg).await;
+        }
+    }
+
+    async fn welcome(&mut self, client_id: ClientId, resumed: bool) {
+        let session = self.client_mut(client_id).session.clone();
+        self.send_to(client_id, &ServerMsg::event(ServerToClient::Welcome {
+            client_id,
+            version: common::VERSION.to_string(),
+            session,
+            resumed,
+        })).await;
+        let clients = self.clients.iter().map(|(id, c)| c.info(*id)).collect();
+        self.send_to(client_id, &ServerMsg::event(ServerToClient::Presence { clients })).await;
+    }
+
+    // For clients whose connections drop without them saying goodbye, which may well be down to a
+    // flaky network, so we give them a chance to come back.
+    async fn detach_client(&mut self, client_id: ClientId) {
+        if self.shutting_down || self.config.session_grace == Duration::from_secs(0) {
+            return self.remove_client(client_id).await
+        }
+        self.client_mut(client_id).conn = Conn::Detached {
+            since: Instant::now(),
+            missed: VecDeque::new(),
+            overflowed: false,
+        };
+        self.update_connected();
+    }
+
+    async fn remove_client(&mut self, client_id: ClientId) {
+        self.clients.remove(&client_id).expect("no client in map");
+        self.update_connected();
+        self.send_all(&ServerMsg::event(ServerToClient::ClientLeft { client_id })).await;
+        for room in self.rooms.leave_all(client_id) {
+            self.send_all(&ServerMsg::event(ServerToClient::RoomEmptied { room })).await;
+        }
+    }
+
+    async fn handle_frame(&mut self, client_id: ClientId, decoded: Result<ClientMsg, DecodeError>) {
+        match decoded {
+            Ok(msg) => {
+                self.metrics.messages_received.inc(msg.body.kind());
+                info!("Server received {:?} from {}", msg, client_id);
+                self.handle_clapi(client_id, msg).await;
+            },
+            Err(e) => {
+                self.metrics.messages_received.inc("Malformed");
+                warn!("Unparseable message from {}: {}", client_id, e);
+                self.send_to(client_id, &ServerMsg::error(None, e.into())).await;
+            }
+        }
     }
 
-    async fn send_all(&mut self, msg: Message) {
-        join_all(self.clients.values_mut().map(
-            |client| client.tx.send(ClientEvent::AppMsg(msg.clone()))
-        )).await;
+    async fn handle_clapi(&mut self, client_id: ClientId, msg: ClientMsg) {
+        let (user, name) = self.clients.get(&client_id).map_or((None, None), |c| (c.user.clone(), c.name.clone()));
+        let actor = Actor { client_id, user: user.as_deref(), name: name.as_deref() };
+        let result = match msg.body {
+            ClientToServer::Broadcast { room, text } => match self.rooms.post(&room, actor, text, SystemTime::now()) {
+                Ok(entry) => {
+                    let msg = ServerMsg::event(ServerToClient::Broadcast {
+                        room: room.clone(), from: entry.from, text: entry.text, seq: entry.seq
+                    });
+                    self.send_room(&room, &msg).await;
+                    Ok(())
+                },
+                Err(e) => Err(e),
+            },
+            ClientToServer::Direct { to, text } => match self.find_clients(&to) {
+                recipients if recipients.is_empty() => Err(ProtocolError::RecipientNotFound { recipient: to }),
+                recipients => {
+                    let direct = ServerMsg::event(ServerToClient::Direct { from: client_id, text });
+                    for recipient_id in recipients {
+                        self.send_to(recipient_id, &direct).await;
+                        self.send_to(client_id, &ServerMsg::reply(msg.id, ServerToClient::Delivered { to: recipient_id })).await;
+                    }
+                    Ok(())
+                },
+            },
+            ClientToServer::JoinRoom { room } => {
+                let joined = self.rooms.join(&room, actor);
+                if joined.created {
+                    self.send_all(&ServerMsg::event(ServerToClient::RoomCreated { room: room.clone() })).await;
+                }
+                let reply = ServerToClient::Joined { room: joined.info, role: joined.role };
+                self.send_to(client_id, &ServerMsg::reply(msg.id, reply)).await;
+                let messages = self.rooms.history(&room, client_id, None, SystemTime::now()).expect("just joined");
+                self.send_to(client_id, &ServerMsg::reply(msg.id, ServerToClient::History { room, messages })).await;
+                Ok(())
+            },
+            ClientToServer::LeaveRoom { room } => match self.rooms.leave(&room, client_id) {
+                Ok(emptied) => {
+                    self.send_to(client_id, &ServerMsg::reply(msg.id, ServerToClient::Left { room: room.clone() })).await;
+                    if emptied {
+                        self.send_all(&ServerMsg::event(ServerToClient::RoomEmptied { room })).await;
+                    }
+                    Ok(())
+                },
+                Err(e) => Err(e),
+            },
+            ClientToServer::DestroyRoom { room } => match self.rooms.destroy(&room, actor) {
+                Ok(()) => {
+                    self.send_all(&ServerMsg::event(ServerToClient::RoomDestroyed { room })).await;
+                    Ok(())
+                },
+                Err(e) => Err(e),
+            },
+            ClientToServer::ListRooms => {
+                let rooms = self.rooms.info();
+                self.send_to(client_id, &ServerMsg::reply(msg.id, ServerToClient::Rooms { rooms })).await;
+                Ok(())
+            },
+            ClientToServer::FetchHistory { room, since } => match self.rooms.history(&room, client_id, since, SystemTime::now()) {
+                Ok(messages) => {
+                    self.send_to(client_id, &ServerMsg::reply(msg.id, ServerToClient::History { room, messages })).await;
+                    Ok(())
+                },
+                Err(e) => Err(e),
+            },
+            ClientToServer::SetName { name } => {
+                // Nobody gets to pass themselves off as someone else's user either:
+                let taken = name.is_some() && self.clients.iter()
+                    .any(|(id, c)| *id != client_id && (c.name == name || c.user == name));
+                match name {
+                    Some(name) if taken => Err(ProtocolError::NameTaken { name }),
+                    name => {
+                        self.client_mut(client_id).name = name.clone();
+                        self.send_all(&ServerMsg::event(ServerToClient::ClientRenamed { client_id, name })).await;
+                        Ok(())
+                    }
+                }
+            },
+            ClientToServer::SetMetadata { metadata } => {
+                let client = self.client_mut(client_id);
+                client.metadata = metadata;
+                let client = client.info(client_id);
+                self.send_all(&ServerMsg::event(ServerToClient::ClientUpdated { client })).await;
+                Ok(())
+            },
+            ClientToServer::GrantRole { room, to, role } => self.change_role(actor, room, to, Some(role)).await,
+            ClientToServer::RevokeRole { room, from } => self.change_role(actor, room, from, None).await,
+            ClientToServer::SetDefaultRole { room, role } => match self.rooms.set_default_role(&room, actor, role) {
+                Ok(()) => {
+                    self.send_room(&room, &ServerMsg::event(ServerToClient::DefaultRoleChanged { room: room.clone(), role })).await;
+                    Ok(())
+                },
+                Err(e) => Err(e),
+            },
+        };
+        if let Err(e) = result {
+            self.send_to(client_id, &ServerMsg::error(Some(msg.id), e)).await;
+        }
     }
+
+    async fn change_role(&mut self, actor: Actor<'_>, room: RoomName, grantee: Grantee, role: Option<RoomRole>) -> Result<(), ProtocolError> {
+        if let Grantee::Client(id) = grantee {
+            if !self.clients.contains_key(&id) {
+                return Err(ProtocolError::RecipientNotFound { recipient: Recipient::Id(id) });
+            }
+        }
+        self.rooms.grant(&room, actor, &grantee, role)?;
+        self.send_room(&room, &ServerMsg::event(ServerToClient::RoleChanged { room: room.clone(), grantee, role })).await;
+        Ok(())
+    }
+
+    // Names ought to be unique already, but anything less than exactly one match gets nobody:
+    fn find_clients(&self, recipient: &Recipient) -> Vec<ClientId> {
+        let matching = |f: &dyn Fn(&Client) -> bool| -> Vec<ClientId> {
+            self.clients.iter().filter(|(_, c)| f(c)).map(|(id, _)| *id).collect()
+        };
+        match recipient {
+            Recipient::Id(id) => self.clients.get(id).map(|_| *id).into_iter().collect(),
+            Recipient::User(user) => matching(&|c| c.user.as_ref() == Some(user)),
+            Recipient::Name(name) => Some(matching(&|c| c.name.as_ref() == Some(name)))
+                .filter(|named| named.len() == 1)
+                .unwrap_or_default(),
+        }
+    }
+
+    fn client_mut(&mut self, client_id: ClientId) -> &mut Client {
+        self.clients.get_mut(&client_id).expect("no client in map")
+    }
+
+    fn update_connected(&self) {
+        let connected = self.clients.values().filter(|c| c.is_attached()).count();
+        self.metrics.clients_connected.store(connected as u64, Ordering::Relaxed);
+    }
+
+    async fn send_to(&mut self, client_id: ClientId, msg: &ServerMsg) {
+        if let Some(client) = self.clients.get_mut(&client_id) {
+            client.send(msg, &self.metrics).await;
+        } else {
+            warn!("Tried to send {:?} to missing client {}", msg, client_id);
+        }
+    }
+
+    async fn send_room(&mut self, room: &str, msg: &ServerMsg) {
+        let members = match self.rooms.get(room) {
+            Some(room) => &room.members,
+            None => return warn!("Tried to send {:?} to missing room {}", msg, room),
+        };
+        let metrics = &self.metrics;
+        join_all(self.clients.iter_mut()
+            .filter(|(id, _)| members.contains(id))
+            .map(|(_, client)| client.send(msg, metrics))
+        ).await;
+    }
+
+    async fn send_all(&mut self, msg: &ServerMsg) {
+        let metrics = &self.metrics;
+        join_all(self.clients.values_mut().map(|client| client.send(msg, metrics))).await;
+    }
+
+    async fn send_all_raw(&mut self, msg: Message) {
+        join_all(self.clients.values_mut().map(|client| client.send_raw(msg.clone()))).await;
+    }
+}
+
+pub(crate) enum AppCmd {
+    NewClient(mpsc::UnboundedSender<ClientEvent>, Handshake),
+    ClientMsg(ClientId, Message),
+    // The connection dropped without a close message. Which connection, in case the client has
+    // already come back on a new one:
+    ClientGone(ClientId, mpsc::UnboundedSender<ClientEvent>),
+    AssetsChanged,
+    Heartbeat,
+    HasRoom(Handshake, oneshot::Sender<bool>),
+    ListClients(oneshot::Sender<Vec<ClientInfo>>),
+    ListRooms(oneshot::Sender<Vec<RoomInfo>>),
+    // Messages from whoever's running the server, rather than from another client:
+    Announce { room: Option<RoomName>, text: String, reply: oneshot::Sender<Result<(), ProtocolError>> },
+    Kick(ClientId, oneshot::Sender<Result<(), ProtocolError>>),
+}
+
+// Sends the app a command carrying a channel for it to reply on, and waits for the answer. Gives
+// None if the app has gone away in the meantime.
+pub(crate) async fn ask<T>(tx: &mpsc::UnboundedSender<AppCmd>, cmd: impl FnOnce(oneshot::Sender<T>) -> AppCmd) -> Option<T> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    tx.unbounded_send(cmd(reply_tx)).ok()?;
+    reply_rx.await.ok()
 }
 
-enum AppCmd {
-    NewClient(mpsc::UnboundedSender<ClientEvent>),
-    ClientMsg(u16, Message),
+// What we learnt about a client while upgrading its connection:
+#[derive(Clone)]
+pub(crate) struct Handshake {
+    pub protocol: Protocol,
+    // The session the client wants to resume, if any:
+    pub session: Option<SessionToken>,
+    // None if we're not authenticating anyone:
+    pub user: Option<String>,
 }
 
 enum AppShutdown { Soft, Hard }
 
+fn watch_client_assets(dir: &Path, mut tx: mpsc::UnboundedSender<AppCmd>) {
+    let paths = resources::CLIENT_ASSETS.iter().map(|asset| dir.join(asset)).collect();
+    let mut changes = watch_paths(paths, DEV_POLL_INTERVAL);
+    info!("Dev mode: watching {} for client changes", dir.display());
+    tokio::task::spawn(async move {
+        while let Some(()) = changes.next().await {
+            // A client build replaces several files, so give it a chance to finish before telling
+            // browsers to come and fetch them:
+            tokio::time::sleep(DEV_POLL_INTERVAL).await;
+            while let Some(Some(())) = changes.next().now_or_never() {}
+            info!("Client assets changed, reloading browsers");
+            if tx.send(AppCmd::AssetsChanged).await.is_err() { break }
+        }
+    });
+}
+
 struct Client {
-    tx: mpsc::UnboundedSender<ClientEvent>
+    conn: Conn,
+    protocol: Protocol,
+    user: Option<String>,
+    name: Option<String>,
+    metadata: BTreeMap<String, String>,
+    // Pings sent since we last heard a pong:
+    missed_pongs: u32,
+    // What the client has to show us to pick up where it left off after losing its connection:
+    session: SessionToken,
+}
+
+enum Conn {
+    Attached(mpsc::UnboundedSender<ClientEvent>),
+    // Lost, but the client might yet come back for it. Overflowed once there's been too much to
+    // hold on to:
+    Detached { since: Instant, missed: VecDeque<ServerMsg>, overflowed: bool },
+}
+
+impl Client {
+    fn info(&self, id: ClientId) -> ClientInfo {
+        ClientInfo { id, user: self.user.clone(), name: self.name.clone(), metadata: self.metadata.clone() }
+    }
+
+    fn is_attached(&self) -> bool {
+        matches!(self.conn, Conn::Attached(_))
+    }
+
+    fn is_connected_to(&self, client_tx: &mpsc::UnboundedSender<ClientEvent>) -> bool {
+        matches!(&self.conn, Conn::Attached(tx) if tx.same_receiver(client_tx))
+    }
+
+    fn can_resume(&self) -> bool {
+        !matches!(self.conn, Conn::Detached { overflowed: true, .. })
+    }
+
+    // Keeps hold of messages for a client that's currently disconnected:
+    async fn send(&mut self, msg: &ServerMsg, metrics: &Metrics) {
+        match &mut self.conn {
+            Conn::Attached(tx) => {
+                let _ = tx.send(ClientEvent::AppMsg(encode(msg, self.protocol.encoding, metrics))).await;
+            },
+            Conn::Detached { missed, overflowed, .. } => {
+                if missed.len() < MAX_MISSED_MESSAGES {
+                    missed.push_back(msg.clone());
+                } else {
+                    *overflowed = true;
+                }
+            },
+        }
+    }
+
+    // Websocket-level messages, which are no use to anyone after the connection they were for:
+    async fn send_raw(&mut self, msg: Message) {
+        if let Conn::Attached(tx) = &mut self.conn {
+            let _ = tx.send(ClientEvent::AppMsg(msg)).await;
+        }
+    }
+}
+
+fn new_session_token() -> SessionToken {
+    base64::encode_config(rand::random::<[u8; 16]>(), base64::URL_SAFE_NO_PAD)
 }
 
-enum ClientEvent {
-    ClientId(u16),
+pub(crate) enum ClientEvent {
+    ClientId(ClientId),
     AppMsg(Message),
 }
 
-fn handle_request(req: Request<Body>, tx: mpsc::UnboundedSender<AppCmd>) -> Result<Response<Body>, http::Error> {
-    if req.method() != http::Method::GET {
-        err_resp(StatusCode::METHOD_NOT_ALLOWED, "".to_string())
-    } else if req.headers().contains_key(header::UPGRADE) {
-        // TODO: The URI scheme doesn't seem to get supplied, so we can't use that to switch
-        // handler :-(
-        handle_ws(&tx, req)
-    } else {
-        resources::handle_get(req)
+fn encode(msg: &ServerMsg, encoding: Encoding, metrics: &Metrics) -> Message {
+    metrics.messages_sent.inc(msg.body.kind());
+    match encoding {
+        Encoding::Json => Message::Text(msg.to_text()),
+        Encoding::Cbor => Message::Binary(msg.to_binary()),
     }
 }
 
-fn handle_ws(tx: &mpsc::UnboundedSender<AppCmd>, mut req: Request<Body>) -> Result<Response<Body>, http::Error> {
-    // This function is called based on the presence of the upgrade header ;-)
+// What route handlers get to work with, besides the request itself:
+#[derive(Clone)]
+pub(crate) struct RequestCtx {
+    pub tx: mpsc::UnboundedSender<AppCmd>,
+    pub config: Arc<Config>,
+    // False once we've started shutting down:
+    pub ready: Arc<AtomicBool>,
+    pub metrics: Arc<Metrics>,
+    pub accounts: Arc<Accounts>,
+}
+
+fn routes(metrics: Arc<Metrics>) -> Router<RequestCtx> {
+    let mut router = Router::new();
+    router.get("/", |req, ctx: RequestCtx| handle_ws(req, ctx))
+        .guard(|req| req.headers().contains_key(header::UPGRADE));
+    router.get("/", |req, ctx: RequestCtx| async move {
+        resources::index(req, ctx.config.tls.is_some())
+    }).wrap(count_hits(metrics.clone()));
+    router.get("/index.html", |req, ctx: RequestCtx| async move {
+        resources::index(req, ctx.config.tls.is_some())
+    }).wrap(count_hits(metrics.clone()));
+    health::add_routes(&mut router);
+    // Before /:asset, which would otherwise take /account:
+    accounts::add_routes(&mut router);
+    router.get("/metrics", |_, ctx: RequestCtx| async move { metrics_resp(&ctx.metrics) });
+    router.get("/:asset", |req, ctx: RequestCtx| async move {
+        resources::client_asset(req, ctx.config.static_dir.as_deref())
+    }).wrap(count_hits(metrics.clone()));
+    api::add_routes(&mut router);
+    router.wrap(time_requests(metrics));
+    router.wrap(log_requests);
+    router
+}
+
+fn log_requests(inner: Handler<RequestCtx>) -> Handler<RequestCtx> {
+    Arc::new(move |req, ctx| {
+        let (method, path) = (req.method().clone(), req.uri().path().to_string());
+        let resp = inner(req, ctx);
+        async move {
+            let resp = resp.await?;
+            debug!("{} {} -> {}", method, path, resp.status());
+            Ok(resp)
+        }.boxed()
+    })
+}
+
+async fn handle_ws(mut req: Request<Body>, ctx: RequestCtx) -> Result<Response<Body>, http::Error> {
+    // This route is guarded on the presence of the upgrade header ;-)
     let upgrade = req.headers().get(header::UPGRADE).unwrap();
     if  upgrade != hv("websocket") {
-        return err_resp(
+        return reject(
+            &ctx, "bad_upgrade",
             StatusCode::BAD_REQUEST,
             format!("Upgrade to non-websocket connection ({}) requested", unhv(upgrade))
         );
     }
+    if !origin_allowed(req.headers(), &ctx.config.allowed_origins) {
+        let origin = req.headers().get(header::ORIGIN).map_or_else(String::new, unhv);
+        return reject(&ctx, "bad_origin", StatusCode::FORBIDDEN, format!("Origin {} not allowed", origin));
+    }
 
     match req.headers().get(header::SEC_WEBSOCKET_VERSION) {
-        None => return err_resp(
+        None => return reject(
+            &ctx, "missing_version",
             StatusCode::BAD_REQUEST,
             "Missing websocket version header".to_string()
         ),
         Some(ws_version) => if ws_version != hv("13") {
-            return err_resp(
+            return reject(
+                &ctx, "bad_version",
                 StatusCode::BAD_REQUEST,
                 format!("Bad websocket version ({}) requested", unhv(ws_version))
             );
         }
     }
-    let expected_protocol = format!("clapi-{}-{}", common::VERSION.major, common::VERSION.minor);
-    match req.headers().get(header::SEC_WEBSOCKET_PROTOCOL) {
-        None => return err_resp(
+    let offered_protocols = header_list(req.headers(), header::SEC_WEBSOCKET_PROTOCOL);
+    if offered_protocols.is_empty() {
+        return reject(
+            &ctx, "missing_protocol",
             StatusCode::BAD_REQUEST,
             "Missing websocket protocol header".to_string()
-        ),
-        Some(requested_protocol) =>
-            if requested_protocol != HeaderValue::from_str(&expected_protocol)? {
-                return err_resp(
-                    StatusCode::BAD_REQUEST,
-                    format!("Bad websocket protocol ({}) requested", unhv(requested_protocol))
-                );
-            }
+        );
     }
-
-    if req.uri().path() != "/" { return err_resp(StatusCode::NOT_FOUND, "".to_string()); }
+    let protocol = match Protocol::negotiate(offered_protocols.iter().copied()) {
+        Some(protocol) => protocol,
+        None => return reject(
+            &ctx, "bad_protocol",
+            StatusCode::BAD_REQUEST,
+            format!("Bad websocket protocol ({}) requested", offered_protocols.join(", "))
+        ),
+    };
 
     let sec_websocket_accept_header = match req.headers().get(header::SEC_WEBSOCKET_KEY) {
-        None => return err_resp(
+        None => return reject(
+            &ctx, "missing_key",
             StatusCode::BAD_REQUEST,
             "Missing websocket key header".to_string()
         ),
         Some(key) => mk_accept_header(key.as_bytes())
     };
 
-    let tx = tx.clone();
+    // Anyone can register, so once we're wanting tokens, people who've logged in with a password
+    // only get in on that if we've been told they can. Otherwise it's down to tokens:
+    let login = if ctx.config.auth.is_none() || ctx.config.allow_logins {
+        ctx.accounts.current_user(&req).await.unwrap_or_else(|e| {
+            error!("Failed to look up login: {}", e);
+            None
+        })
+    } else {
+        None
+    };
+    let user = match (login, &ctx.config.auth) {
+        (Some(account), _) => Some(accounts::account_user(&account)),
+        (None, None) => None,
+        (None, Some(auth)) => match auth::authenticate(&req, &auth.secret.0) {
+            Ok(claims) if accounts::is_account_user(&claims.user) => return reject(
+                &ctx, "bad_token",
+                StatusCode::UNAUTHORIZED,
+                "Tokens can't be for accounts".to_string()
+            ),
+            Ok(claims) => Some(claims.user),
+            Err(e) => return reject(&ctx, e.reason(), StatusCode::UNAUTHORIZED, e.to_string()),
+        },
+    };
+    let handshake = Handshake {
+        protocol,
+        session: query_param(req.uri(), "session").map(str::to_string),
+        user,
+    };
+
+    // Better to turn clients away now with a proper HTTP error than have them find out after
+    // they've connected. The app still checks again, in case someone else gets in first:
+    if !ctx.ready.load(Ordering::Relaxed) {
+        return reject(&ctx, "shutting_down", StatusCode::SERVICE_UNAVAILABLE, "Shutting down".to_string());
+    }
+    match ask(&ctx.tx, |reply| AppCmd::HasRoom(handshake.clone(), reply)).await {
+        Some(true) => (),
+        Some(false) => return reject(&ctx, "full", StatusCode::SERVICE_UNAVAILABLE, "Server full".to_string()),
+        None => return reject(&ctx, "shutting_down", StatusCode::SERVICE_UNAVAILABLE, "Shutting down".to_string()),
+    }
+
+    let ws_config = WebSocketConfig {
+        max_message_size: Some(ctx.config.limits.max_message_size),
+        ..Default::default()
+    };
+    let limiter = ClientLimiter::new(&ctx.config.limits, Instant::now());
     tokio::task::spawn(async move {
         match hyper::upgrade::on(&mut req).await {
             Ok(upgraded) => {
-                if let Err(e) = websocket_dialogue(tx, upgraded).await {
+                if let Err(e) = websocket_dialogue(ctx.tx, upgraded, handshake, ws_config, limiter, ctx.metrics).await {
                     error!("server websocket IO error: {}", e)
                 }
             },
@@ -242,46 +915,440 @@ fn handle_ws(tx: &mpsc::UnboundedSender<AppCmd>, mut req: Request<Body>) -> Resu
         .header(header::UPGRADE, "websocket")
         .header(header::CONNECTION, "Upgrade")
         .header(header::SEC_WEBSOCKET_ACCEPT, sec_websocket_accept_header)
-        .header(header::SEC_WEBSOCKET_PROTOCOL, expected_protocol)
+        .header(header::SEC_WEBSOCKET_PROTOCOL, protocol.to_string())
         .body(Body::empty())
 }
 
+// Browsers let any page open a websocket to anywhere, cookies and all, so it's down to us to turn
+// away pages we don't trust. Anything that isn't a browser won't send an Origin, and can't be
+// tricked into using someone else's cookies, so gets let through.
+fn origin_allowed(headers: &header::HeaderMap, allowed: &[String]) -> bool {
+    let origin = match headers.get(header::ORIGIN).map(|o| o.to_str()) {
+        None => return true,
+        Some(Ok(origin)) => origin,
+        Some(Err(_)) => return false,
+    };
+    if allowed.is_empty() {
+        // Without knowing our public address, the best we can do for "the same origin" is check
+        // that the page came from wherever it's connecting to:
+        let origin_host = origin.split_once("://").map(|(_, host)| host);
+        let host = headers.get(header::HOST).and_then(|h| h.to_str().ok());
+        return matches!((origin_host, host), (Some(o), Some(h)) if o.eq_ignore_ascii_case(h));
+    }
+    allowed.iter().any(|a| a == "*" || a.eq_ignore_ascii_case(origin))
+}
+
+fn reject(ctx: &RequestCtx, reason: &str, code: StatusCode, message: String) -> Result<Response<Body>, http::Error> {
+    ctx.metrics.handshakes_rejected.inc(reason);
+    err_resp(code, message)
+}
 
-async fn websocket_dialogue(mut app_tx: mpsc::UnboundedSender<AppCmd>, upgraded: hyper::upgrade::Upgraded) -> Result<(), hyper::Error> {
-    let (mut ws_tx, ws_rx) = WebSocketStream::from_raw_socket(upgraded, Role::Server, Default::default())
+async fn websocket_dialogue<S: AsyncRead + AsyncWrite + Unpin>(mut app_tx: mpsc::UnboundedSender<AppCmd>, upgraded: S, handshake: Handshake, ws_config: WebSocketConfig, mut limiter: ClientLimiter, metrics: Arc<Metrics>) -> Result<(), hyper::Error> {
+    let (mut ws_tx, ws_rx) = WebSocketStream::from_raw_socket(upgraded, Role::Server, Some(ws_config))
         .await.split();
-    let (client_tx, client_rx) = mpsc::unbounded();
-    app_tx.send(AppCmd::NewClient(client_tx)).await;
-    let mut client_id = None;
+    let (client_tx, mut client_rx) = mpsc::unbounded();
+    let encoding = handshake.protocol.encoding;
+    let _ = app_tx.send(AppCmd::NewClient(client_tx.clone(), handshake)).await;
 
+    // Until the app either lets the client in or turns it away, there's nobody to hand what it
+    // says to, so it can wait:
+    let mut client_id = match client_rx.next().await {
+        Some(ClientEvent::ClientId(id)) => id,
+        Some(ClientEvent::AppMsg(Message::Close(frame))) => {
+            let _ = ws_tx.send(Message::Close(frame)).await;
+            return Ok(())
+        },
+        // The app never says anything else first, and without one it must have gone away:
+        _ => {
+            let _ = ws_tx.send(Message::Close(None)).await;
+            return Ok(())
+        },
+    };
+
+    // Tack a marker on the end of the client's stream, so that we notice the connection going
+    // away even if the client didn't get to say goodbye:
+    let ws_rx = ws_rx.map(Some).chain(stream::once(ready(None)));
     let mut both = stream::select(
-        ws_rx.map(|x| Left(x)),
-        client_rx.map(|x| Right(x))
+        ws_rx.map(Left),
+        client_rx.map(Right)
     );
     loop {
         match both.next().await {
-            Some(Left(ws_data)) => match ws_data {
+            Some(Left(None)) => {
+                // The app ignores this if the client already closed properly:
+                let _ = app_tx.send(AppCmd::ClientGone(client_id, client_tx)).await;
+                break Ok(())
+            },
+            Some(Left(Some(ws_data))) => match ws_data {
                 Ok(msg) => {
+                    metrics.bytes_received.fetch_add(msg.len() as u64, Ordering::Relaxed);
                     // if let Message::Close(ref x) = msg {
                         // // Make sure we tell the client we accept their close:
                         // ws_tx.send(msg.clone()).await.expect("le fail");
                     // }
-                    app_tx.send(AppCmd::ClientMsg(client_id.unwrap(), msg)).await;
+                    // Only the messages that make work for us count, not the ones answering ours:
+                    let limited = matches!(msg, Message::Text(_) | Message::Binary(_) | Message::Ping(_));
+                    match if limited { limiter.check(msg.len(), Instant::now()) } else { Verdict::Allow } {
+                        Verdict::Allow => {
+                            let _ = app_tx.send(AppCmd::ClientMsg(client_id, msg)).await;
+                        },
+                        Verdict::SlowDown(wait) => {
+                            metrics.rate_limited.inc("message");
+                            let error = ProtocolError::SlowDown { retry_after_ms: wait.as_millis() as u64 };
+                            let _ = ws_tx.send(encode(&ServerMsg::error(None, error), encoding, &metrics)).await;
+                        },
+                        Verdict::Disconnect => {
+                            metrics.rate_limited.inc("client");
+                            warn!("Disconnecting client {} for ignoring its rate limits", client_id);
+                            let _ = ws_tx.send(Message::Close(Some(CloseFrame {
+                                code: CloseCode::Policy,
+                                reason: "Too many messages".into(),
+                            }))).await;
+                            // As if the client had closed the connection itself, so that it can't
+                            // pick up where it left off:
+                            let _ = app_tx.send(AppCmd::ClientMsg(client_id, Message::Close(None))).await;
+                            break Ok(())
+                        },
+                    }
                 },
                 Err(e) => error!("Server errored! {:?}", e)
             },
             Some(Right(client_event)) => match client_event {
-                ClientEvent::ClientId(id) => client_id = Some(id),
+                ClientEvent::ClientId(id) => client_id = id,
                 ClientEvent::AppMsg(msg) => match msg {
                     Message::Close(x) => {
-                        ws_tx.send(Message::Close(x)).await.expect("moar fail sending");
+                        let _ = ws_tx.send(Message::Close(x)).await;
                         warn!("App told client it was closing, terminating dialogue");
                         break Ok(())
                     },
-                    _ => ws_tx.send(msg).await.expect("how can sending fail?"),
+                    _ => {
+                        metrics.bytes_sent.fetch_add(msg.len() as u64, Ordering::Relaxed);
+                        // If the connection's gone, we'll find out from the other half:
+                        if let Err(e) = ws_tx.send(msg).await {
+                            warn!("Failed to send to client {}: {}", client_id, e);
+                        }
+                    },
                 }
             },
             None => break Ok(())
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::config::Args,
+        structopt::StructOpt,
+        tokio::time::{self, Instant},
+    };
+
+    fn app(args: &[&str]) -> mpsc::UnboundedSender<AppCmd> {
+        request_ctx(args).tx
+    }
+
+    // Runs an app, and gives back what request handlers would get to talk to it:
+    fn request_ctx(args: &[&str]) -> RequestCtx {
+        let args = Args::from_iter_safe(std::iter::once("server").chain(args.iter().copied())).unwrap();
+        let app = App::new(Config::from_args(args).unwrap()).unwrap();
+        let (tx, rx) = mpsc::unbounded();
+        let (_, shutdown_rx) = mpsc::channel(1);
+        let ctx = RequestCtx {
+            tx: tx.clone(),
+            config: app.config.clone(),
+            ready: app.ready.clone(),
+            metrics: app.metrics.clone(),
+            accounts: app.accounts.clone(),
+        };
+        tokio::task::spawn(app.app_main(rx, shutdown_rx));
+        ctx
+    }
+
+    type ClientChannel = (mpsc::UnboundedSender<ClientEvent>, mpsc::UnboundedReceiver<ClientEvent>);
+
+    // Gives back the welcome, along with both ends of the channel the app talks to the client on:
+    async fn join(tx: &mpsc::UnboundedSender<AppCmd>, session: Option<SessionToken>) -> (ServerToClient, ClientChannel) {
+        join_as(tx, session, None).await
+    }
+
+    async fn join_as(tx: &mpsc::UnboundedSender<AppCmd>, session: Option<SessionToken>, user: Option<&str>) -> (ServerToClient, ClientChannel) {
+        let (client_tx, mut client_rx) = mpsc::unbounded();
+        let user = user.map(str::to_string);
+        let handshake = Handshake { protocol: Protocol::current(Encoding::Json), session, user };
+        tx.unbounded_send(AppCmd::NewClient(client_tx.clone(), handshake)).unwrap();
+        match client_rx.next().await {
+            Some(ClientEvent::ClientId(_)) => (),
+            _ => panic!("expected a client id"),
+        };
+        let welcome = next_body(&mut client_rx).await;
+        match next_body(&mut client_rx).await {
+            ServerToClient::Presence { .. } => (),
+            body => panic!("expected presence, got {:?}", body),
+        }
+        (welcome, (client_tx, client_rx))
+    }
+
+    async fn connect(tx: &mpsc::UnboundedSender<AppCmd>) -> (ClientId, mpsc::UnboundedReceiver<ClientEvent>) {
+        match join(tx, None).await {
+            (ServerToClient::Welcome { client_id, .. }, (_, rx)) => (client_id, rx),
+            (body, _) => panic!("expected welcome, got {:?}", body),
+        }
+    }
+
+    async fn next_msg(rx: &mut mpsc::UnboundedReceiver<ClientEvent>) -> Message {
+        match rx.next().await {
+            Some(ClientEvent::AppMsg(msg)) => msg,
+            _ => panic!("expected a message"),
+        }
+    }
+
+    async fn next_body(rx: &mut mpsc::UnboundedReceiver<ClientEvent>) -> ServerToClient {
+        match next_msg(rx).await {
+            Message::Text(s) => ServerMsg::from_text(&s).unwrap().body,
+            msg => panic!("expected a clapi message, got {:?}", msg),
+        }
+    }
+
+    fn send(tx: &mpsc::UnboundedSender<AppCmd>, client_id: ClientId, body: ClientToServer) {
+        let msg = ClientMsg { id: 0, body }.to_text();
+        tx.unbounded_send(AppCmd::ClientMsg(client_id, Message::Text(msg))).unwrap();
+    }
+
+    fn ws_request() -> http::request::Builder {
+        Request::builder()
+            .header(header::HOST, "concert.example:8080")
+            .header(header::UPGRADE, "websocket")
+            .header(header::SEC_WEBSOCKET_VERSION, "13")
+            .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+            .header(header::SEC_WEBSOCKET_PROTOCOL, Protocol::current(Encoding::Json).to_string())
+    }
+
+    async fn handshake_status(ctx: &RequestCtx, origin: Option<&str>) -> StatusCode {
+        let mut req = ws_request();
+        if let Some(origin) = origin {
+            req = req.header(header::ORIGIN, origin);
+        }
+        handle_ws(req.body(Body::empty()).unwrap(), ctx.clone()).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_origin() {
+        let ctx = request_ctx(&[]);
+        assert_eq!(handshake_status(&ctx, Some("http://concert.example:8080")).await, StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(handshake_status(&ctx, Some("https://Concert.Example:8080")).await, StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(handshake_status(&ctx, None).await, StatusCode::SWITCHING_PROTOCOLS);
+        for origin in ["https://evil.example", "http://concert.example:8081", "http://concert.example", "null"].iter() {
+            assert_eq!(handshake_status(&ctx, Some(origin)).await, StatusCode::FORBIDDEN, "{}", origin);
+        }
+
+        let ctx = request_ctx(&["--allowed-origins", "https://app.example,https://other.example/"]);
+        assert_eq!(handshake_status(&ctx, Some("https://app.example")).await, StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(handshake_status(&ctx, Some("https://other.example")).await, StatusCode::SWITCHING_PROTOCOLS);
+        // Once there's a list, even the server's own origin has to be on it:
+        assert_eq!(handshake_status(&ctx, Some("http://concert.example:8080")).await, StatusCode::FORBIDDEN);
+        assert_eq!(handshake_status(&ctx, Some("http://app.example")).await, StatusCode::FORBIDDEN);
+
+        let ctx = request_ctx(&["--allowed-origins", "*"]);
+        assert_eq!(handshake_status(&ctx, Some("https://evil.example")).await, StatusCode::SWITCHING_PROTOCOLS);
+    }
+
+    #[tokio::test]
+    async fn test_logins_with_tokens() {
+        let register = |ctx: &RequestCtx| {
+            let req = Request::post("/account/register")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"user": "paul", "password": "correct horse"}"#))
+                .unwrap();
+            routes(ctx.metrics.clone()).handle(req, ctx.clone()).map(Result::unwrap)
+        };
+        // Gives back the status of a websocket handshake from whoever registered:
+        let connect = |ctx: &RequestCtx, resp: Response<Body>| {
+            let cookie = resp.headers()[header::SET_COOKIE].to_str().unwrap().split(';').next().unwrap().to_string();
+            let req = ws_request().header(header::COOKIE, cookie).body(Body::empty()).unwrap();
+            handle_ws(req, ctx.clone()).map(|resp| resp.unwrap().status())
+        };
+        let secret = "0123456789abcdef";
+
+        // Registering doesn't get anyone past the need for a token:
+        let ctx = request_ctx(&["--auth-secret", secret]);
+        let resp = register(&ctx).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(connect(&ctx, resp).await, StatusCode::UNAUTHORIZED);
+
+        // ...unless we've said it should:
+        let ctx = request_ctx(&["--auth-secret", secret, "--allow-logins"]);
+        let resp = register(&ctx).await;
+        assert_eq!(connect(&ctx, resp).await, StatusCode::SWITCHING_PROTOCOLS);
+
+        let ctx = request_ctx(&["--close-registration"]);
+        assert_eq!(register(&ctx).await.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_api_needs_admin_token() {
+        let status = |ctx: &RequestCtx, auth: Option<&str>| {
+            let mut req = Request::builder().uri("/api/clients");
+            if let Some(auth) = auth {
+                req = req.header(header::AUTHORIZATION, auth);
+            }
+            routes(ctx.metrics.clone()).handle(req.body(Body::empty()).unwrap(), ctx.clone())
+                .map(|resp| resp.unwrap().status())
+        };
+        let ctx = request_ctx(&[]);
+        assert_eq!(status(&ctx, Some("Bearer 0123456789abcdef")).await, StatusCode::FORBIDDEN);
+
+        let ctx = request_ctx(&["--admin-token", "0123456789abcdef"]);
+        assert_eq!(status(&ctx, None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(&ctx, Some("Bearer fedcba9876543210")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(&ctx, Some("Bearer 0123456789abcdef")).await, StatusCode::OK);
+    }
+
+    // The handshake checks there's room, but the app can still fill up before the client's let in:
+    #[tokio::test]
+    async fn test_turned_away() {
+        let ctx = request_ctx(&["--max-clients", "1"]);
+        let _first = connect(&ctx.tx).await;
+        let (server_end, client_end) = tokio::io::duplex(4096);
+        let handshake = Handshake { protocol: Protocol::current(Encoding::Json), session: None, user: None };
+        let limiter = ClientLimiter::new(&ctx.config.limits, Instant::now());
+        let dialogue = tokio::task::spawn(websocket_dialogue(
+            ctx.tx.clone(), server_end, handshake, WebSocketConfig::default(), limiter, ctx.metrics.clone()
+        ));
+
+        // Saying something before hearing back mustn't take the dialogue down:
+        let mut ws = WebSocketStream::from_raw_socket(client_end, Role::Client, None).await;
+        ws.send(Message::Text("{}".to_string())).await.unwrap();
+        match ws.next().await {
+            Some(Ok(Message::Close(Some(frame)))) => assert_eq!(frame.reason, "Server full"),
+            msg => panic!("expected close, got {:?}", msg),
+        }
+        dialogue.await.unwrap().unwrap();
+    }
+
+    // With the clock paused, tokio skips ahead to the next timer whenever there's nothing else to
+    // do, so these run instantly but still see time passing.
+    #[tokio::test]
+    async fn test_heartbeat() {
+        time::pause();
+        let start = Instant::now();
+        let tx = app(&["--heartbeat-interval", "10", "--heartbeat-misses", "2", "--session-grace", "0"]);
+        let (id, mut rx) = connect(&tx).await;
+
+        assert_eq!(next_msg(&mut rx).await, Message::Ping(Vec::new()));
+        assert_eq!(start.elapsed().as_secs(), 10);
+        tx.unbounded_send(AppCmd::ClientMsg(id, Message::Pong(Vec::new()))).unwrap();
+
+        // Having answered, the client gets let off two more pings before being shown the door:
+        for secs in [20, 30].iter() {
+            assert_eq!(next_msg(&mut rx).await, Message::Ping(Vec::new()));
+            assert_eq!(start.elapsed().as_secs(), *secs);
+        }
+        match next_msg(&mut rx).await {
+            Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Policy),
+            msg => panic!("expected close, got {:?}", msg),
+        }
+        assert_eq!(start.elapsed().as_secs(), 40);
+        assert_eq!(ask(&tx, AppCmd::ListClients).await, Some(Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn test_direct() {
+        let tx = app(&[]);
+        let paul = || async {
+            match join_as(&tx, None, Some("~paul")).await {
+                (ServerToClient::Welcome { client_id, .. }, (_, rx)) => (client_id, rx),
+                (body, _) => panic!("expected welcome, got {:?}", body),
+            }
+        };
+        let (a, mut a_rx) = paul().await;
+        let (b, mut b_rx) = paul().await;
+        let (c, mut c_rx) = connect(&tx).await;
+        // Everyone hears about the newcomers:
+        next_body(&mut a_rx).await;
+        next_body(&mut a_rx).await;
+        next_body(&mut b_rx).await;
+
+        // Users get direct messages on every client they're connected on:
+        send(&tx, c, ClientToServer::Direct { to: Recipient::User("~paul".to_string()), text: "hi".to_string() });
+        for rx in [&mut a_rx, &mut b_rx].iter_mut() {
+            assert_eq!(next_body(rx).await, ServerToClient::Direct { from: c, text: "hi".to_string() });
+        }
+        assert_eq!(next_body(&mut c_rx).await, ServerToClient::Delivered { to: a });
+        assert_eq!(next_body(&mut c_rx).await, ServerToClient::Delivered { to: b });
+
+        // And nobody can name themselves after them to get their messages instead:
+        send(&tx, c, ClientToServer::SetName { name: Some("~paul".to_string()) });
+        match next_body(&mut c_rx).await {
+            ServerToClient::Error { error: ProtocolError::NameTaken { .. } } => (),
+            body => panic!("expected name taken, got {:?}", body),
+        }
+        let to = Recipient::Name("~paul".to_string());
+        send(&tx, c, ClientToServer::Direct { to: to.clone(), text: "hi".to_string() });
+        assert_eq!(
+            next_body(&mut c_rx).await,
+            ServerToClient::Error { error: ProtocolError::RecipientNotFound { recipient: to } }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_vanished_client() {
+        time::pause();
+        let tx = app(&["--session-grace", "0"]);
+        let (welcome, (client_tx, rx)) = join(&tx, None).await;
+        let id = match welcome { ServerToClient::Welcome { client_id, .. } => client_id, _ => unreachable!() };
+        let _other = connect(&tx).await;
+        drop(rx);
+        // What websocket_dialogue tells us when the connection just stops:
+        tx.unbounded_send(AppCmd::ClientGone(id, client_tx.clone())).unwrap();
+        tx.unbounded_send(AppCmd::ClientGone(id, client_tx)).unwrap();
+        assert_eq!(ask(&tx, AppCmd::ListClients).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_resume() {
+        time::pause();
+        let tx = app(&["--heartbeat-interval", "10", "--heartbeat-misses", "10", "--session-grace", "30"]);
+        let (welcome, (a_tx, a_rx)) = join(&tx, None).await;
+        let (a, session) = match welcome {
+            ServerToClient::Welcome { client_id, session, resumed: false, .. } => (client_id, session),
+            body => panic!("expected welcome, got {:?}", body),
+        };
+        let (b, mut b_rx) = connect(&tx).await;
+
+        // A loses its connection, and misses a message while it's away:
+        drop(a_rx);
+        tx.unbounded_send(AppCmd::ClientGone(a, a_tx.clone())).unwrap();
+        send(&tx, b, ClientToServer::Direct { to: Recipient::Id(a), text: "psst".to_string() });
+        assert_eq!(next_body(&mut b_rx).await, ServerToClient::Delivered { to: a });
+
+        let (welcome, (a_tx2, mut a_rx)) = join(&tx, Some(session.clone())).await;
+        let session2 = match welcome {
+            ServerToClient::Welcome { client_id, session, resumed: true, .. } if client_id == a => session,
+            body => panic!("expected resumed welcome, got {:?}", body),
+        };
+        assert_ne!(session2, session);
+        assert_eq!(next_body(&mut a_rx).await, ServerToClient::Direct { from: b, text: "psst".to_string() });
+        // Word of the old connection dropping arriving late doesn't count against the new one:
+        tx.unbounded_send(AppCmd::ClientGone(a, a_tx)).unwrap();
+        send(&tx, b, ClientToServer::Direct { to: Recipient::Id(a), text: "hi".to_string() });
+        assert_eq!(next_body(&mut a_rx).await, ServerToClient::Direct { from: b, text: "hi".to_string() });
+
+        // Sessions can only be resumed once:
+        match join(&tx, Some(session)).await.0 {
+            ServerToClient::Welcome { client_id, resumed: false, .. } => assert_ne!(client_id, a),
+            body => panic!("expected fresh welcome, got {:?}", body),
+        }
+
+        // And not once the grace period's up:
+        drop(a_rx);
+        tx.unbounded_send(AppCmd::ClientGone(a, a_tx2)).unwrap();
+        time::sleep(Duration::from_secs(45)).await;
+        let clients = ask(&tx, AppCmd::ListClients).await.unwrap();
+        assert!(clients.iter().all(|c| c.id != a));
+        match join(&tx, Some(session2)).await.0 {
+            ServerToClient::Welcome { resumed, .. } => assert!(!resumed),
+            body => panic!("expected welcome, got {:?}", body),
+        }
+    }
+}
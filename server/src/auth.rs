@@ -0,0 +1,151 @@
+use {
+    crypto::{hmac::Hmac, mac::{Mac, MacResult}, sha2::Sha256},
+    hyper::{Body, Request, header},
+    serde::{Deserialize, Serialize},
+    std::{
+        fmt,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
+};
+
+use crate::hyper_helpers::query_param;
+
+pub const TOKEN_COOKIE: &str = "concert_token";
+
+// Tokens are "<payload>.<signature>", both base64url encoded, where the payload is these claims
+// as JSON and the signature is an HMAC-SHA256 of the encoded payload.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Claims {
+    pub user: String,
+    // Seconds since the epoch:
+    pub expires: u64,
+}
+
+pub fn issue(secret: &[u8], user: &str, ttl: Duration, now: SystemTime) -> String {
+    let claims = Claims { user: user.to_string(), expires: unix_secs(now + ttl) };
+    let payload = encode(&serde_json::to_vec(&claims).expect("unserialisable"));
+    let signature = encode(sign(secret, &payload).code());
+    format!("{}.{}", payload, signature)
+}
+
+pub fn verify(secret: &[u8], token: &str, now: SystemTime) -> Result<Claims, AuthError> {
+    let mut parts = token.splitn(2, '.');
+    let (payload, signature) = match (parts.next(), parts.next()) {
+        (Some(p), Some(s)) => (p, decode(s)?),
+        _ => return Err(AuthError::Malformed),
+    };
+    // MacResult compares in constant time:
+    if sign(secret, payload) != MacResult::new_from_owned(signature) {
+        return Err(AuthError::BadSignature);
+    }
+    let claims: Claims = serde_json::from_slice(&decode(payload)?).map_err(|_| AuthError::Malformed)?;
+    if claims.expires <= unix_secs(now) {
+        return Err(AuthError::Expired);
+    }
+    Ok(claims)
+}
+
+// Browsers can't set headers on websocket requests, so the token comes in the query string or,
+// for pages that have logged in, a cookie.
+pub fn authenticate(req: &Request<Body>, secret: &[u8]) -> Result<Claims, AuthError> {
+    let token = query_param(req.uri(), "token")
+        .or_else(|| cookie(req, TOKEN_COOKIE))
+        .ok_or(AuthError::Missing)?;
+    verify(secret, token, SystemTime::now())
+}
+
+pub fn cookie<'a>(req: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    req.headers().get_all(header::COOKIE).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|pair| {
+            let mut kv = pair.trim().splitn(2, '=');
+            if kv.next()? == name { kv.next() } else { None }
+        })
+}
+
+fn sign(secret: &[u8], payload: &str) -> MacResult {
+    let mut hmac = Hmac::new(Sha256::new(), secret);
+    hmac.input(payload.as_bytes());
+    hmac.result()
+}
+
+fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode(s: &str) -> Result<Vec<u8>, AuthError> {
+    base64::decode_config(s, base64::URL_SAFE_NO_PAD).map_err(|_| AuthError::Malformed)
+}
+
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    Missing,
+    Malformed,
+    BadSignature,
+    Expired,
+}
+
+impl AuthError {
+    // For metrics:
+    pub fn reason(&self) -> &'static str {
+        match self {
+            AuthError::Missing => "missing_token",
+            AuthError::Malformed | AuthError::BadSignature => "bad_token",
+            AuthError::Expired => "expired_token",
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            AuthError::Missing => "Missing authentication token",
+            AuthError::Malformed => "Malformed authentication token",
+            AuthError::BadSignature => "Invalid authentication token",
+            AuthError::Expired => "Authentication token has expired",
+        })
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"not a very secret secret";
+
+    #[test]
+    fn test_verify() {
+        let now = SystemTime::now();
+        let token = issue(SECRET, "paul", Duration::from_secs(60), now);
+        assert_eq!(verify(SECRET, &token, now).unwrap().user, "paul");
+        assert_eq!(verify(SECRET, &token, now + Duration::from_secs(60)), Err(AuthError::Expired));
+        assert_eq!(verify(b"some other secret", &token, now), Err(AuthError::BadSignature));
+        assert_eq!(verify(SECRET, "nonsense", now), Err(AuthError::Malformed));
+
+        // Changing who it's for invalidates the signature:
+        let forged = issue(SECRET, "admin", Duration::from_secs(60), now);
+        let token = format!("{}.{}", forged.split('.').next().unwrap(), token.split('.').nth(1).unwrap());
+        assert_eq!(verify(SECRET, &token, now), Err(AuthError::BadSignature));
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let token = issue(SECRET, "paul", Duration::from_secs(60), SystemTime::now());
+        let req = |uri: &str, cookie: &str| Request::builder()
+            .uri(uri)
+            .header(header::COOKIE, cookie)
+            .body(Body::empty())
+            .unwrap();
+        let user = |req| authenticate(&req, SECRET).map(|c| c.user);
+        assert_eq!(user(req(&format!("/?token={}", token), "")), Ok("paul".to_string()));
+        assert_eq!(user(req("/", &format!("a=b; concert_token={}", token))), Ok("paul".to_string()));
+        assert_eq!(user(req("/", "a=b")), Err(AuthError::Missing));
+    }
+}
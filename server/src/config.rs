@@ -0,0 +1,595 @@
+use {
+    log::LevelFilter,
+    serde::Deserialize,
+    std::{
+        collections::BTreeMap,
+        error::Error,
+        fmt,
+        fs::read_to_string,
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        path::{Path, PathBuf},
+        str::FromStr,
+        time::Duration,
+    },
+    structopt::StructOpt,
+};
+
+use crate::accounts::{is_account_user, ACCOUNT_PREFIX};
+
+// Settings come from (in increasing order of precedence): the defaults below, an optional TOML
+// config file, CONCERT_* environment variables and finally the command line.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "server", about = "Concert websocket server")]
+pub struct Args {
+    /// TOML file to read settings from
+    #[structopt(short, long, env = "CONCERT_CONFIG", parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// IP address to listen on (may be given more than once) [default: 0.0.0.0]
+    #[structopt(short, long, env = "CONCERT_LISTEN", use_delimiter = true)]
+    listen: Vec<IpAddr>,
+    /// Port to listen on [default: 8080]
+    #[structopt(short, long, env = "CONCERT_PORT")]
+    port: Option<u16>,
+    /// Log level, either for everything (e.g. "debug") or per module (e.g. "hyper=warn")
+    #[structopt(long, env = "CONCERT_LOG", use_delimiter = true)]
+    log: Vec<LogDirective>,
+    /// Serve the client's assets from this directory instead of the built-in copies
+    #[structopt(long, env = "CONCERT_STATIC_DIR", parse(from_os_str))]
+    static_dir: Option<PathBuf>,
+    /// Directory to keep rooms and their history in, so that they survive restarts [default: keep
+    /// them in memory]
+    #[structopt(long, env = "CONCERT_DATA_DIR", parse(from_os_str))]
+    data_dir: Option<PathBuf>,
+    /// Development mode: serve the client's assets from disk and reload browsers when they change
+    #[structopt(long, env = "CONCERT_DEV", value_name = "BOOL", require_equals = true)]
+    dev: Option<Option<Switch>>,
+    /// PEM certificate chain for TLS
+    #[structopt(long, env = "CONCERT_TLS_CERT", parse(from_os_str))]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key for TLS
+    #[structopt(long, env = "CONCERT_TLS_KEY", parse(from_os_str))]
+    tls_key: Option<PathBuf>,
+    /// Seconds a client gets to complete its TLS handshake before being disconnected [default: 10]
+    #[structopt(long, env = "CONCERT_TLS_HANDSHAKE_TIMEOUT")]
+    tls_handshake_timeout: Option<u64>,
+    /// Maximum number of simultaneously connected clients [default: 1024]
+    #[structopt(long, env = "CONCERT_MAX_CLIENTS")]
+    max_clients: Option<usize>,
+    /// Maximum size of a single websocket message, in bytes [default: 65536]
+    #[structopt(long, env = "CONCERT_MAX_MESSAGE_SIZE")]
+    max_message_size: Option<usize>,
+    /// Messages each client may send per second, on average, or 0 for no limit [default: 20]
+    #[structopt(long, env = "CONCERT_MESSAGE_RATE")]
+    message_rate: Option<u32>,
+    /// Bytes each client may send per second, on average, or 0 for no limit [default: 65536]
+    #[structopt(long, env = "CONCERT_BYTE_RATE")]
+    byte_rate: Option<usize>,
+    /// Connections each IP address may make per minute, or 0 for no limit [default: 60]
+    #[structopt(long, env = "CONCERT_CONNECTION_RATE")]
+    connection_rate: Option<u32>,
+    /// Password logins and registrations each IP address may attempt per minute, or 0 for no
+    /// limit [default: 10]
+    #[structopt(long, env = "CONCERT_LOGIN_RATE")]
+    login_rate: Option<u32>,
+    /// Seconds between pings to each client [default: 30]
+    #[structopt(long, env = "CONCERT_HEARTBEAT_INTERVAL")]
+    heartbeat_interval: Option<u64>,
+    /// Number of unanswered pings after which a client is disconnected [default: 3]
+    #[structopt(long, env = "CONCERT_HEARTBEAT_MISSES")]
+    heartbeat_misses: Option<u32>,
+    /// Seconds to hold on to a disconnected client's id and messages in case it reconnects, or 0
+    /// to forget about clients as soon as they go [default: 60]
+    #[structopt(long, env = "CONCERT_SESSION_GRACE")]
+    session_grace: Option<u64>,
+    /// Number of broadcasts each room remembers for latecomers, or 0 for none [default: 100]
+    #[structopt(long, env = "CONCERT_HISTORY_SIZE")]
+    history_size: Option<usize>,
+    /// Seconds for which rooms remember each broadcast [default: 3600]
+    #[structopt(long, env = "CONCERT_HISTORY_AGE")]
+    history_age: Option<u64>,
+    /// Secret for signing authentication tokens. Without one, anyone can connect
+    #[structopt(long, env = "CONCERT_AUTH_SECRET", hide_env_values = true)]
+    auth_secret: Option<String>,
+    /// Seconds for which issued authentication tokens are valid [default: 86400]
+    #[structopt(long, env = "CONCERT_TOKEN_TTL")]
+    token_ttl: Option<u64>,
+    /// Seconds for which a password login lasts [default: 604800]
+    #[structopt(long, env = "CONCERT_LOGIN_TTL")]
+    login_ttl: Option<u64>,
+    /// Stop anyone registering new password accounts
+    #[structopt(long, env = "CONCERT_CLOSE_REGISTRATION", value_name = "BOOL", require_equals = true)]
+    close_registration: Option<Option<Switch>>,
+    /// Let people logged in with a password connect without a token, even with an auth secret
+    #[structopt(long, env = "CONCERT_ALLOW_LOGINS", value_name = "BOOL", require_equals = true)]
+    allow_logins: Option<Option<Switch>>,
+    /// Token that scripts must give as a bearer token to use the /api endpoints. Without one, the
+    /// API is turned off
+    #[structopt(long, env = "CONCERT_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+    /// Origins (e.g. "https://example.com") of pages allowed to open websockets, or "*" for any
+    /// [default: the same origin as the server]
+    #[structopt(long, env = "CONCERT_ALLOWED_ORIGINS", use_delimiter = true)]
+    allowed_origins: Vec<String>,
+    /// Print an authentication token for the given user and exit
+    #[structopt(long, value_name = "USER")]
+    issue_token: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct FileConfig {
+    listen: Option<Vec<IpAddr>>,
+    port: Option<u16>,
+    log: Option<Vec<LogDirective>>,
+    static_dir: Option<PathBuf>,
+    data_dir: Option<PathBuf>,
+    dev: Option<bool>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    tls_handshake_timeout: Option<u64>,
+    max_clients: Option<usize>,
+    max_message_size: Option<usize>,
+    message_rate: Option<u32>,
+    byte_rate: Option<usize>,
+    connection_rate: Option<u32>,
+    login_rate: Option<u32>,
+    heartbeat_interval: Option<u64>,
+    heartbeat_misses: Option<u32>,
+    session_grace: Option<u64>,
+    history_size: Option<usize>,
+    history_age: Option<u64>,
+    auth_secret: Option<String>,
+    token_ttl: Option<u64>,
+    login_ttl: Option<u64>,
+    close_registration: Option<bool>,
+    allow_logins: Option<bool>,
+    admin_token: Option<String>,
+    allowed_origins: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub listen: Vec<SocketAddr>,
+    pub log: Vec<LogDirective>,
+    pub static_dir: Option<PathBuf>,
+    pub data_dir: Option<PathBuf>,
+    pub dev: bool,
+    pub tls: Option<TlsConfig>,
+    pub limits: Limits,
+    pub heartbeat: Heartbeat,
+    // How long disconnected clients get to come back and resume their session:
+    pub session_grace: Duration,
+    pub history: History,
+    pub auth: Option<Auth>,
+    // How long users who log in with a password stay logged in:
+    pub login_ttl: Duration,
+    pub open_registration: bool,
+    // Whether a password login is enough to connect when we'd otherwise want a token:
+    pub allow_logins: bool,
+    // None if the API's turned off:
+    pub admin_token: Option<Secret>,
+    // Empty if only pages from the server itself can connect:
+    pub allowed_origins: Vec<String>,
+    pub issue_token: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub handshake_timeout: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Limits {
+    pub max_clients: usize,
+    pub max_message_size: usize,
+    // Rates are per second for each client, except for connections and logins, which are per
+    // minute for each IP address. 0 means no limit:
+    pub messages_per_sec: u32,
+    pub bytes_per_sec: usize,
+    pub connections_per_min: u32,
+    pub logins_per_min: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_clients: 1024,
+            max_message_size: 64 << 10,
+            messages_per_sec: 20,
+            bytes_per_sec: 64 << 10,
+            connections_per_min: 60,
+            logins_per_min: 10,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub max_missed: u32,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat { interval: Duration::from_secs(30), max_missed: 3 }
+    }
+}
+
+// How much of what's said in each room is kept around:
+#[derive(Clone, Debug, PartialEq)]
+pub struct History {
+    pub max_messages: usize,
+    pub max_age: Duration,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History { max_messages: 100, max_age: Duration::from_secs(60 * 60) }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Auth {
+    pub secret: Secret,
+    pub token_ttl: Duration,
+}
+
+// So that it doesn't end up in logs:
+#[derive(Clone, PartialEq)]
+pub struct Secret(pub Vec<u8>);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::from_args(Args::from_args())
+    }
+
+    pub fn from_args(args: Args) -> Result<Self, ConfigError> {
+        let file = match &args.config {
+            Some(path) => FileConfig::load(path)?,
+            None => FileConfig::default(),
+        };
+        let default_limits = Limits::default();
+        let default_heartbeat = Heartbeat::default();
+        let default_history = History::default();
+
+        let listen = Some(args.listen).filter(|l| !l.is_empty())
+            .or(file.listen)
+            .unwrap_or_else(|| vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)]);
+        let port = args.port.or(file.port).unwrap_or(8080);
+        // Later directives override earlier ones, so these defaults just quieten down some of our
+        // chattier dependencies unless asked otherwise:
+        let mut log: Vec<LogDirective> = ["mio=warn", "tokio_tungstenite=warn", "tungstenite=warn"]
+            .iter().map(|d| d.parse().unwrap()).collect();
+        log.extend(file.log.unwrap_or_default());
+        log.extend(args.log);
+        let handshake_timeout = Duration::from_secs(
+            args.tls_handshake_timeout.or(file.tls_handshake_timeout).unwrap_or(10)
+        );
+        let tls = match (args.tls_cert.or(file.tls_cert), args.tls_key.or(file.tls_key)) {
+            (None, None) => None,
+            (Some(cert), Some(key)) => Some(TlsConfig { cert, key, handshake_timeout }),
+            _ => return Err(ConfigError::new("TLS needs both a certificate and a key")),
+        };
+        let dev = switch(args.dev).or(file.dev).unwrap_or(false);
+        // In dev mode we default to the directory the client gets built into in this checkout:
+        let static_dir = args.static_dir.or(file.static_dir).or_else(|| if dev {
+            Some(Path::new(env!("CARGO_MANIFEST_DIR")).join("../client/static"))
+        } else {
+            None
+        });
+        let token_ttl = Duration::from_secs(args.token_ttl.or(file.token_ttl).unwrap_or(24 * 60 * 60));
+        let config = Config {
+            listen: listen.into_iter().map(|ip| SocketAddr::new(ip, port)).collect(),
+            log,
+            static_dir,
+            data_dir: args.data_dir.or(file.data_dir),
+            dev,
+            tls,
+            limits: Limits {
+                max_clients: args.max_clients.or(file.max_clients)
+                    .unwrap_or(default_limits.max_clients),
+                max_message_size: args.max_message_size.or(file.max_message_size)
+                    .unwrap_or(default_limits.max_message_size),
+                messages_per_sec: args.message_rate.or(file.message_rate)
+                    .unwrap_or(default_limits.messages_per_sec),
+                bytes_per_sec: args.byte_rate.or(file.byte_rate)
+                    .unwrap_or(default_limits.bytes_per_sec),
+                connections_per_min: args.connection_rate.or(file.connection_rate)
+                    .unwrap_or(default_limits.connections_per_min),
+                logins_per_min: args.login_rate.or(file.login_rate)
+                    .unwrap_or(default_limits.logins_per_min),
+            },
+            heartbeat: Heartbeat {
+                interval: args.heartbeat_interval.or(file.heartbeat_interval)
+                    .map_or(default_heartbeat.interval, Duration::from_secs),
+                max_missed: args.heartbeat_misses.or(file.heartbeat_misses)
+                    .unwrap_or(default_heartbeat.max_missed),
+            },
+            session_grace: Duration::from_secs(args.session_grace.or(file.session_grace).unwrap_or(60)),
+            history: History {
+                max_messages: args.history_size.or(file.history_size)
+                    .unwrap_or(default_history.max_messages),
+                max_age: args.history_age.or(file.history_age)
+                    .map_or(default_history.max_age, Duration::from_secs),
+            },
+            auth: args.auth_secret.or(file.auth_secret).map(|secret| Auth {
+                secret: Secret(secret.into_bytes()),
+                token_ttl,
+            }),
+            login_ttl: Duration::from_secs(args.login_ttl.or(file.login_ttl).unwrap_or(7 * 24 * 60 * 60)),
+            open_registration: !switch(args.close_registration).or(file.close_registration).unwrap_or(false),
+            allow_logins: switch(args.allow_logins).or(file.allow_logins).unwrap_or(false),
+            admin_token: args.admin_token.or(file.admin_token).map(|token| Secret(token.into_bytes())),
+            // Browsers never put a trailing slash on origins, but people might:
+            allowed_origins: Some(args.allowed_origins).filter(|o| !o.is_empty())
+                .or(file.allowed_origins)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|origin| origin.trim_end_matches('/').to_string())
+                .collect(),
+            issue_token: args.issue_token,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(dir) = &self.static_dir {
+            if !dir.is_dir() {
+                return Err(ConfigError(format!("static dir {} is not a directory", dir.display())));
+            }
+        }
+        if let Some(tls) = &self.tls {
+            for path in [&tls.cert, &tls.key].iter() {
+                if !path.is_file() {
+                    return Err(ConfigError(format!("TLS file {} does not exist", path.display())));
+                }
+            }
+            if tls.handshake_timeout == Duration::from_secs(0) {
+                return Err(ConfigError::new("tls-handshake-timeout must be at least 1"));
+            }
+        }
+        match &self.auth {
+            Some(auth) if auth.secret.0.len() < 16 =>
+                return Err(ConfigError::new("auth-secret must be at least 16 bytes")),
+            None if self.issue_token.is_some() =>
+                return Err(ConfigError::new("issue-token needs an auth-secret to sign with")),
+            _ if self.issue_token.as_deref().is_some_and(is_account_user) =>
+                return Err(ConfigError(format!("issue-token users can't start with {}", ACCOUNT_PREFIX))),
+            _ => (),
+        }
+        if self.admin_token.as_ref().is_some_and(|token| token.0.len() < 16) {
+            return Err(ConfigError::new("admin-token must be at least 16 bytes"));
+        }
+        for origin in self.allowed_origins.iter() {
+            if origin != "*" && !origin.starts_with("http://") && !origin.starts_with("https://") {
+                return Err(ConfigError(format!("allowed origin {:?} should look like https://example.com", origin)));
+            }
+        }
+        if self.limits.max_clients == 0 {
+            return Err(ConfigError::new("max-clients must be at least 1"));
+        }
+        if self.heartbeat.interval == Duration::from_secs(0) {
+            return Err(ConfigError::new("heartbeat-interval must be at least 1"));
+        }
+        if self.heartbeat.max_missed == 0 {
+            return Err(ConfigError::new("heartbeat-misses must be at least 1"));
+        }
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> LevelFilter {
+        self.log.iter().rev()
+            .find(|d| d.module.is_none())
+            .map_or(LevelFilter::Info, |d| d.level)
+    }
+
+    pub fn module_levels(&self) -> BTreeMap<&str, LevelFilter> {
+        self.log.iter().filter_map(|d| d.module.as_deref().map(|m| (m, d.level))).collect()
+    }
+}
+
+impl FileConfig {
+    fn load(path: &Path) -> Result<Self, ConfigError> {
+        let s = read_to_string(path)
+            .map_err(|e| ConfigError(format!("couldn't read {}: {}", path.display(), e)))?;
+        toml::from_str(&s)
+            .map_err(|e| ConfigError(format!("bad config file {}: {}", path.display(), e)))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct LogDirective {
+    pub module: Option<String>,
+    pub level: LevelFilter,
+}
+
+impl FromStr for LogDirective {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (module, level) = match s.find('=') {
+            Some(i) => (Some(s[..i].trim().to_string()), &s[i + 1..]),
+            None => (None, s),
+        };
+        let level = level.trim().parse()
+            .map_err(|_| ConfigError(format!("bad log level in {:?}", s)))?;
+        Ok(LogDirective { module, level })
+    }
+}
+
+impl std::convert::TryFrom<String> for LogDirective {
+    type Error = ConfigError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+// Clap ignores environment variables for plain flags, so ours take an optional value instead:
+// `--dev` alone turns it on, while `--dev=false` or CONCERT_DEV=false can turn off one set by a
+// lower layer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Switch(bool);
+
+impl FromStr for Switch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "true" | "yes" | "on" | "1" => Ok(Switch(true)),
+            "false" | "no" | "off" | "0" | "" => Ok(Switch(false)),
+            _ => Err(format!("expected true or false, not {:?}", s)),
+        }
+    }
+}
+
+fn switch(arg: Option<Option<Switch>>) -> Option<bool> {
+    arg.map(|value| value.unwrap_or(Switch(true)).0)
+}
+
+#[derive(Debug)]
+pub struct ConfigError(String);
+
+impl ConfigError {
+    fn new(msg: &str) -> Self {
+        ConfigError(msg.to_string())
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for ConfigError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(a: &[&str]) -> Args {
+        Args::from_iter_safe(std::iter::once("server").chain(a.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = Config::from_args(args(&[])).unwrap();
+        assert_eq!(config.listen, vec![SocketAddr::from(([0, 0, 0, 0], 8080))]);
+        assert_eq!(config.log_level(), LevelFilter::Info);
+        assert_eq!(config.tls, None);
+        assert_eq!(config.static_dir, None);
+        assert_eq!(config.data_dir, None);
+        assert!(!config.dev);
+        assert_eq!(config.limits, Limits::default());
+        assert_eq!(config.heartbeat, Heartbeat::default());
+        assert_eq!(config.session_grace, Duration::from_secs(60));
+        assert_eq!(config.history, History::default());
+        assert_eq!(config.auth, None);
+        assert_eq!(config.login_ttl, Duration::from_secs(7 * 24 * 60 * 60));
+        assert_eq!(config.admin_token, None);
+        assert!(config.open_registration);
+        assert!(!config.allow_logins);
+        assert!(config.allowed_origins.is_empty());
+    }
+
+    #[test]
+    fn test_args() {
+        let config = Config::from_args(args(&[
+            "-l", "127.0.0.1", "--listen", "::1", "-p", "9000",
+            "--log", "debug,hyper=warn,mio=info", "--max-clients", "3", "--session-grace", "0",
+            "--allowed-origins", "https://a.example/,http://b.example:8000",
+        ])).unwrap();
+        assert_eq!(
+            config.listen,
+            vec!["127.0.0.1:9000".parse().unwrap(), "[::1]:9000".parse().unwrap()]
+        );
+        assert_eq!(config.log_level(), LevelFilter::Debug);
+        let module_levels = config.module_levels();
+        assert_eq!(module_levels["hyper"], LevelFilter::Warn);
+        assert_eq!(module_levels["mio"], LevelFilter::Info);
+        assert_eq!(module_levels["tungstenite"], LevelFilter::Warn);
+        assert_eq!(config.limits.max_clients, 3);
+        assert_eq!(config.limits.messages_per_sec, 20);
+        assert_eq!(config.session_grace, Duration::from_secs(0));
+        assert_eq!(config.allowed_origins, vec!["https://a.example", "http://b.example:8000"]);
+    }
+
+    #[test]
+    fn test_dev() {
+        let config = Config::from_args(args(&["--dev"])).unwrap();
+        assert!(config.dev);
+        assert!(config.static_dir.unwrap().ends_with("client/static"));
+    }
+
+    #[test]
+    fn test_switches() {
+        let config = Config::from_args(args(&["--close-registration", "--allow-logins=yes", "--dev=false"])).unwrap();
+        assert!(!config.open_registration);
+        assert!(config.allow_logins);
+        assert!(!config.dev);
+        assert!(Args::from_iter_safe(&["server", "--dev=maybe"]).is_err());
+        // Without the `=`, what follows is never taken for the switch's value:
+        assert!(Args::from_iter_safe(&["server", "--dev", "false"]).is_err());
+        // Other tests read the real environment, so just check the variables are hooked up:
+        let mut help = Vec::new();
+        Args::clap().write_long_help(&mut help).unwrap();
+        let help: String = String::from_utf8(help).unwrap().split_whitespace().collect();
+        for var in &["CONCERT_DEV", "CONCERT_CLOSE_REGISTRATION", "CONCERT_ALLOW_LOGINS"] {
+            assert!(help.contains(&format!("[env:{}", var)), "{} isn't read", var);
+        }
+    }
+
+    #[test]
+    fn test_file() {
+        let file: FileConfig = toml::from_str(r#"
+            port = 1234
+            log = ["warn", "server=trace"]
+            max-message-size = 10
+            heartbeat-interval = 5
+            history-size = 0
+            connection-rate = 0
+        "#).unwrap();
+        assert_eq!(file.port, Some(1234));
+        assert_eq!(file.heartbeat_interval, Some(5));
+        assert_eq!(file.history_size, Some(0));
+        assert_eq!(file.connection_rate, Some(0));
+        assert_eq!(file.log.unwrap()[1], LogDirective {
+            module: Some("server".to_string()),
+            level: LevelFilter::Trace
+        });
+        assert!(toml::from_str::<FileConfig>("prot = 1234").is_err());
+        assert!(toml::from_str::<FileConfig>(r#"log = ["loud"]"#).is_err());
+    }
+
+    #[test]
+    fn test_validation() {
+        assert!(Config::from_args(args(&["--tls-cert", "cert.pem"])).is_err());
+        assert!(Config::from_args(args(&["--static-dir", "/no/such/dir"])).is_err());
+        assert!(Config::from_args(args(&[
+            "--tls-cert", "testdata/localhost.cert.pem", "--tls-key", "testdata/localhost.key.pem",
+            "--tls-handshake-timeout", "0",
+        ])).is_err());
+        assert!(Config::from_args(args(&["--max-clients", "0"])).is_err());
+        assert!(Config::from_args(args(&["--heartbeat-interval", "0"])).is_err());
+        assert!(Config::from_args(args(&["--heartbeat-misses", "0"])).is_err());
+        assert!(Config::from_args(args(&["--auth-secret", "short"])).is_err());
+        assert!(Config::from_args(args(&["--admin-token", "short"])).is_err());
+        assert!(Config::from_args(args(&["--issue-token", "paul"])).is_err());
+        assert!(Config::from_args(args(&["--auth-secret", "0123456789abcdef", "--issue-token", "~paul"])).is_err());
+        assert!(Config::from_args(args(&["--allowed-origins", "example.com"])).is_err());
+        let config = Config::from_args(args(&["--auth-secret", "0123456789abcdef", "--issue-token", "paul"]));
+        assert!(!format!("{:?}", config.unwrap()).contains("0123456789abcdef"));
+        assert!(Args::from_iter_safe(vec!["server", "--log", "loud"]).is_err());
+    }
+}
@@ -0,0 +1,57 @@
+use {
+    hyper::{Body, Response, StatusCode, header},
+    serde::Serialize,
+    std::sync::atomic::Ordering,
+};
+
+use {
+    common::clapi::{Encoding, Protocol},
+    crate::{
+        app::RequestCtx,
+        hyper_helpers::server_header,
+        router::{HandlerResult, Router},
+    },
+};
+
+// For process managers and load balancers. These are deliberately cheap, and answered without
+// involving app_main, so that a busy app doesn't look like a dead one.
+pub fn add_routes(router: &mut Router<RequestCtx>) {
+    // If we can answer at all, we're alive:
+    router.get("/healthz", |_, _| async { text_resp(StatusCode::OK, "ok") });
+    // ...but we stop being ready for new clients as soon as we start shutting down:
+    router.get("/readyz", |_, ctx: RequestCtx| async move {
+        if ctx.ready.load(Ordering::Relaxed) {
+            text_resp(StatusCode::OK, "ready")
+        } else {
+            text_resp(StatusCode::SERVICE_UNAVAILABLE, "shutting down")
+        }
+    });
+    router.get("/version", |_, _| async {
+        let info = VersionInfo {
+            version: common::VERSION.to_string(),
+            protocol: Protocol::current(Encoding::Json).to_string(),
+            server: server_header(),
+        };
+        Response::builder()
+            .header(header::SERVER, server_header())
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CACHE_CONTROL, "no-store")
+            .body(Body::from(serde_json::to_vec(&info).expect("unserialisable")))
+    });
+}
+
+#[derive(Serialize)]
+struct VersionInfo {
+    version: String,
+    protocol: String,
+    server: String,
+}
+
+fn text_resp(status: StatusCode, text: &'static str) -> HandlerResult {
+    Response::builder()
+        .status(status)
+        .header(header::SERVER, server_header())
+        .header(header::CONTENT_TYPE, "text/plain")
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(Body::from(text))
+}
@@ -33,6 +33,17 @@ pub fn unhv(hv: &header::HeaderValue) -> String {
     }
 }
 
+// Splits up headers like Sec-WebSocket-Protocol that may be given as a comma-separated list,
+// possibly spread over several instances of the header.
+pub fn header_list(headers: &header::HeaderMap, name: header::HeaderName) -> Vec<&str> {
+    headers.get_all(name).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
 pub fn server_header() -> String {
     format!("Concert/{}", common::VERSION)
 }
@@ -47,6 +58,15 @@ pub fn err_resp(code: StatusCode, message: String) -> Result<Response<Body>, htt
         .body(Body::from(message))
 }
 
+// Finds a parameter in a query string. There's no percent-decoding, so this is only fit for
+// values we've handed out ourselves in the first place.
+pub fn query_param<'a>(uri: &'a http::Uri, name: &str) -> Option<&'a str> {
+    uri.query()?.split('&').find_map(|pair| {
+        let mut kv = pair.splitn(2, '=');
+        if kv.next()? == name { Some(kv.next().unwrap_or("")) } else { None }
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -56,4 +76,25 @@ mod tests {
         // Example taken from https://en.wikipedia.org/wiki/WebSocket
         assert_eq!(mk_accept_header(b"x3JJHMbDL1EzLkh9GBhXDw=="), "HSmrc0sMlYUkAGmm5OPpG2HaGWk=")
     }
+
+    #[test]
+    fn test_header_list() {
+        let mut headers = header::HeaderMap::new();
+        assert_eq!(header_list(&headers, header::SEC_WEBSOCKET_PROTOCOL), Vec::<&str>::new());
+        headers.append(header::SEC_WEBSOCKET_PROTOCOL, hv("clapi-0-2+cbor, clapi-0-2 ,,"));
+        headers.append(header::SEC_WEBSOCKET_PROTOCOL, hv("chat"));
+        assert_eq!(
+            header_list(&headers, header::SEC_WEBSOCKET_PROTOCOL),
+            vec!["clapi-0-2+cbor", "clapi-0-2", "chat"]
+        );
+    }
+
+    #[test]
+    fn test_query_param() {
+        let uri: http::Uri = "/?a=1&session=abc-_&flag".parse().unwrap();
+        assert_eq!(query_param(&uri, "session"), Some("abc-_"));
+        assert_eq!(query_param(&uri, "flag"), Some(""));
+        assert_eq!(query_param(&uri, "b"), None);
+        assert_eq!(query_param(&"/".parse().unwrap(), "a"), None);
+    }
 }
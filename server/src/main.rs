@@ -1,29 +1,50 @@
 use {
-    log::{info, LevelFilter},
+    log::info,
     simple_logger::SimpleLogger,
-    std::net::SocketAddr,
+    std::{process::exit, time::SystemTime},
     tokio::signal::unix::{signal, SignalKind},
 };
 
+mod accounts;
+mod api;
 mod app;
+mod auth;
+mod config;
+mod health;
 mod hyper_helpers;
+mod metrics;
 mod resources;
+mod ratelimit;
+mod rooms;
+mod router;
 mod service;
+mod storage;
+mod tls;
+mod watch;
 
-use common;
-use crate::app::App;
+use crate::{app::App, config::Config};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    SimpleLogger::new()
-        .with_module_level("mio", LevelFilter::Warn)
-        .with_module_level("tokio_tungstenite", LevelFilter::Warn)
-        .with_module_level("tungstenite", LevelFilter::Warn)
-        .init()
-        .unwrap();
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Bad configuration: {}", e);
+            exit(2)
+        }
+    };
+    if let (Some(user), Some(auth)) = (&config.issue_token, &config.auth) {
+        println!("{}", auth::issue(&auth.secret.0, user, auth.token_ttl, SystemTime::now()));
+        return Ok(())
+    }
+    let logger = config.module_levels().into_iter().fold(
+        SimpleLogger::new().with_level(config.log_level()),
+        |logger, (module, level)| logger.with_module_level(module, level)
+    );
+    logger.init().unwrap();
     info!("Version: {}", common::VERSION);
     let sigint = signal(SignalKind::interrupt()).expect("failed to set up signal handler");
-    let app = App::new(sigint);
-    app.serve(&SocketAddr::from(([0, 0, 0, 0], 8080))).await?;
+    let app = App::new(config)?;
+    app.serve(sigint).await?;
     Ok(())
 }
@@ -0,0 +1,218 @@
+use {
+    futures::FutureExt,
+    hyper::{Body, Response, StatusCode, header},
+    std::{
+        collections::BTreeMap,
+        fmt::Write,
+        sync::{
+            Arc, RwLock,
+            atomic::{AtomicU64, Ordering::Relaxed},
+        },
+        time::Instant,
+    },
+};
+
+use crate::{
+    hyper_helpers::server_header,
+    router::{Handler, HandlerResult, MatchedRoute},
+};
+
+// Upper bounds of the request latency histogram buckets, in seconds:
+const LATENCY_BUCKETS: [f64; 10] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0];
+
+// Everything's an atomic, so that whoever's doing the counting (app_main included) never has to
+// wait for whoever's reading, and vice versa.
+#[derive(Default)]
+pub struct Metrics {
+    pub clients_connected: AtomicU64,
+    pub messages_received: Counters,
+    pub messages_sent: Counters,
+    pub bytes_received: AtomicU64,
+    pub bytes_sent: AtomicU64,
+    pub heartbeat_evictions: AtomicU64,
+    pub handshakes_rejected: Counters,
+    pub rate_limited: Counters,
+    pub asset_hits: Counters,
+    request_latency: RwLock<BTreeMap<String, Histogram>>,
+}
+
+// A family of counters distinguished by a single label. The write lock is only needed the first
+// time we see each label, which with the handful of labels we use happens almost never.
+#[derive(Default)]
+pub struct Counters(RwLock<BTreeMap<String, AtomicU64>>);
+
+impl Counters {
+    pub fn inc(&self, label: &str) {
+        self.add(label, 1)
+    }
+
+    pub fn add(&self, label: &str, n: u64) {
+        if let Some(c) = self.0.read().expect("poisoned").get(label) {
+            c.fetch_add(n, Relaxed);
+            return
+        }
+        self.0.write().expect("poisoned")
+            .entry(label.to_string())
+            .or_default()
+            .fetch_add(n, Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, label: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+        for (value, c) in self.0.read().expect("poisoned").iter() {
+            let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, escape(value), c.load(Relaxed));
+        }
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, secs: f64) {
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            if secs <= *bound {
+                bucket.fetch_add(1, Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Relaxed);
+        self.sum_micros.fetch_add((secs * 1e6) as u64, Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn observe_latency(&self, route: &str, secs: f64) {
+        if let Some(h) = self.request_latency.read().expect("poisoned").get(route) {
+            return h.observe(secs);
+        }
+        self.request_latency.write().expect("poisoned")
+            .entry(route.to_string())
+            .or_default()
+            .observe(secs);
+    }
+
+    // Renders everything in the Prometheus text exposition format:
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let gauges = [
+            ("concert_clients_connected", "Number of connected websocket clients", &self.clients_connected),
+        ];
+        for (name, help, value) in gauges.iter() {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value.load(Relaxed));
+        }
+        let counters = [
+            ("concert_bytes_received_total", "Websocket payload bytes received from clients", &self.bytes_received),
+            ("concert_bytes_sent_total", "Websocket payload bytes sent to clients", &self.bytes_sent),
+            ("concert_heartbeat_evictions_total", "Clients disconnected for missing heartbeats", &self.heartbeat_evictions),
+        ];
+        for (name, help, value) in counters.iter() {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value.load(Relaxed));
+        }
+        self.messages_received.render(
+            &mut out, "concert_messages_received_total", "type", "Messages received from clients");
+        self.messages_sent.render(
+            &mut out, "concert_messages_sent_total", "type", "Messages sent to clients");
+        self.handshakes_rejected.render(
+            &mut out, "concert_handshakes_rejected_total", "reason", "Websocket handshakes turned away");
+        self.rate_limited.render(
+            &mut out, "concert_rate_limited_total", "kind", "Messages, clients, connections and logins turned away for going too fast");
+        self.asset_hits.render(
+            &mut out, "concert_asset_hits_total", "path", "Requests for static assets");
+
+        let name = "concert_http_request_duration_seconds";
+        let _ = writeln!(out, "# HELP {} HTTP request latency\n# TYPE {} histogram", name, name);
+        for (route, h) in self.request_latency.read().expect("poisoned").iter() {
+            let route = escape(route);
+            for (bound, bucket) in LATENCY_BUCKETS.iter().zip(h.buckets.iter()) {
+                let _ = writeln!(out, "{}_bucket{{route=\"{}\",le=\"{}\"}} {}", name, route, bound, bucket.load(Relaxed));
+            }
+            let count = h.count.load(Relaxed);
+            let _ = writeln!(out, "{}_bucket{{route=\"{}\",le=\"+Inf\"}} {}", name, route, count);
+            let sum = h.sum_micros.load(Relaxed) as f64 / 1e6;
+            let _ = writeln!(out, "{}_sum{{route=\"{}\"}} {}\n{}_count{{route=\"{}\"}} {}", name, route, sum, name, route, count);
+        }
+        out
+    }
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+pub fn metrics_resp(metrics: &Metrics) -> HandlerResult {
+    Response::builder()
+        .header(header::SERVER, server_header())
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(Body::from(metrics.render()))
+}
+
+// Middleware that times requests, labelled by the route pattern they matched so that the number
+// of histograms doesn't grow with whatever paths people throw at us:
+pub fn time_requests<C: 'static>(metrics: Arc<Metrics>) -> impl Fn(Handler<C>) -> Handler<C> {
+    move |inner: Handler<C>| -> Handler<C> {
+        let metrics = metrics.clone();
+        Arc::new(move |req, ctx| {
+            let route = req.extensions().get::<MatchedRoute>().map_or("", |r| &r.0).to_string();
+            let start = Instant::now();
+            let metrics = metrics.clone();
+            inner(req, ctx).map(move |resp| {
+                metrics.observe_latency(&route, start.elapsed().as_secs_f64());
+                resp
+            }).boxed()
+        })
+    }
+}
+
+// Middleware for the static asset routes. Only counts paths we actually have something for, for
+// the same reason as above:
+pub fn count_hits<C: 'static>(metrics: Arc<Metrics>) -> impl Fn(Handler<C>) -> Handler<C> {
+    move |inner: Handler<C>| -> Handler<C> {
+        let metrics = metrics.clone();
+        Arc::new(move |req, ctx| {
+            let path = req.uri().path().to_string();
+            let metrics = metrics.clone();
+            inner(req, ctx).map(move |resp| {
+                if let Ok(r) = &resp {
+                    if r.status() != StatusCode::NOT_FOUND {
+                        metrics.asset_hits.inc(&path);
+                    }
+                }
+                resp
+            }).boxed()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let m = Metrics::new();
+        m.clients_connected.store(2, Relaxed);
+        m.messages_received.inc("Broadcast");
+        m.messages_received.add("Broadcast", 2);
+        m.handshakes_rejected.inc("bad \"version\"");
+        m.observe_latency("/", 0.002);
+        m.observe_latency("/", 2.0);
+        let out = m.render();
+        assert!(out.contains("# TYPE concert_clients_connected gauge\nconcert_clients_connected 2\n"));
+        assert!(out.contains("concert_messages_received_total{type=\"Broadcast\"} 3\n"));
+        assert!(out.contains("concert_handshakes_rejected_total{reason=\"bad \\\"version\\\"\"} 1\n"));
+        assert!(out.contains("concert_http_request_duration_seconds_bucket{route=\"/\",le=\"0.001\"} 0\n"));
+        assert!(out.contains("concert_http_request_duration_seconds_bucket{route=\"/\",le=\"0.0025\"} 1\n"));
+        assert!(out.contains("concert_http_request_duration_seconds_bucket{route=\"/\",le=\"+Inf\"} 2\n"));
+        assert!(out.contains("concert_http_request_duration_seconds_sum{route=\"/\"} 2.002\n"));
+        assert!(out.contains("concert_http_request_duration_seconds_count{route=\"/\"} 2\n"));
+    }
+}
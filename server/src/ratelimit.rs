@@ -0,0 +1,216 @@
+use {
+    std::{
+        collections::HashMap,
+        net::IpAddr,
+        sync::{Arc, Mutex},
+        time::Duration,
+    },
+    tokio::time::Instant,
+};
+
+use crate::{config::Limits, metrics::Metrics};
+
+// How many seconds' worth of its allowance a client can use up all at once:
+const BURST_SECS: f64 = 5.0;
+// How many times a client can be told to slow down in quick succession before we give up on it,
+// and how long it takes us to forget each time:
+const PATIENCE: f64 = 10.0;
+const FORGIVE_EVERY: Duration = Duration::from_secs(10);
+// Past this many addresses we start forgetting about the ones that haven't connected lately:
+const MAX_TRACKED_ADDRS: usize = 4096;
+
+// Lets through bursts of up to the capacity at once, then refills at the rate (per second).
+// Asking for more than the capacity works once the bucket's full, leaving it in debt:
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: f64, rate: f64, now: Instant) -> Self {
+        TokenBucket { capacity, rate, tokens: capacity, last: now }
+    }
+
+    // Takes n tokens if there are enough, otherwise says how long until there will be:
+    pub fn take(&mut self, n: f64, now: Instant) -> Result<(), Duration> {
+        let wait = self.wait(n, now);
+        if wait == Duration::ZERO {
+            self.tokens -= n;
+            return Ok(());
+        }
+        Err(wait)
+    }
+
+    fn wait(&mut self, n: f64, now: Instant) -> Duration {
+        self.refill(now);
+        let n = n.min(self.capacity);
+        if self.tokens >= n {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((n - self.tokens) / self.rate)
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Allow,
+    // With how long until the message would have been allowed:
+    SlowDown(Duration),
+    Disconnect,
+}
+
+// Keeps a single connection to its share of messages and bytes, so that nobody can use the
+// rooms to drown everybody else out.
+pub struct ClientLimiter {
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    patience: TokenBucket,
+}
+
+impl ClientLimiter {
+    pub fn new(limits: &Limits, now: Instant) -> Self {
+        let bucket = |rate: f64| Some(rate)
+            .filter(|rate| *rate > 0.0)
+            .map(|rate| TokenBucket::new(rate * BURST_SECS, rate, now));
+        ClientLimiter {
+            messages: bucket(limits.messages_per_sec as f64),
+            bytes: bucket(limits.bytes_per_sec as f64),
+            patience: TokenBucket::new(PATIENCE, 1.0 / FORGIVE_EVERY.as_secs_f64(), now),
+        }
+    }
+
+    // Messages only count against the limits if they're allowed through:
+    pub fn check(&mut self, len: usize, now: Instant) -> Verdict {
+        let len = len as f64;
+        let wait = self.messages.as_mut().map_or(Duration::ZERO, |b| b.wait(1.0, now))
+            .max(self.bytes.as_mut().map_or(Duration::ZERO, |b| b.wait(len, now)));
+        if wait == Duration::ZERO {
+            if let Some(b) = &mut self.messages { b.tokens -= 1.0 }
+            if let Some(b) = &mut self.bytes { b.tokens -= len }
+            return Verdict::Allow;
+        }
+        match self.patience.take(1.0, now) {
+            Ok(()) => Verdict::SlowDown(wait),
+            Err(_) => Verdict::Disconnect,
+        }
+    }
+}
+
+// Limits how often each remote address can do something (connect, or try a password), so that
+// nobody can tie us up just by doing it over and over. Everyone behind the same proxy or NAT
+// shares an allowance, so it may want raising (or turning off) for deployments like that.
+pub struct AddrLimiter {
+    per_minute: u32,
+    addrs: Mutex<HashMap<IpAddr, TokenBucket>>,
+    // What's being limited, as far as the metrics are concerned:
+    what: &'static str,
+    metrics: Arc<Metrics>,
+}
+
+impl AddrLimiter {
+    // None if there's no limit:
+    pub fn new(per_minute: u32, what: &'static str, metrics: Arc<Metrics>) -> Option<Self> {
+        if per_minute == 0 {
+            return None;
+        }
+        Some(AddrLimiter { per_minute, addrs: Mutex::new(HashMap::new()), what, metrics })
+    }
+
+    // Ok if the address can go ahead, otherwise how long it'll have to wait:
+    pub fn check(&self, addr: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut addrs = self.addrs.lock().expect("poisoned");
+        if addrs.len() >= MAX_TRACKED_ADDRS && !addrs.contains_key(&addr) {
+            // Anyone whose allowance has fully recovered is no different to someone we've never
+            // seen before:
+            addrs.retain(|_, bucket| !bucket.is_full(now));
+        }
+        let per_minute = self.per_minute as f64;
+        let result = addrs.entry(addr)
+            .or_insert_with(|| TokenBucket::new(per_minute, per_minute / 60.0, now))
+            .take(1.0, now);
+        if result.is_err() {
+            self.metrics.rate_limited.inc(self.what);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let mut bucket = TokenBucket::new(2.0, 4.0, start);
+        assert_eq!(bucket.take(1.0, start), Ok(()));
+        assert_eq!(bucket.take(1.0, start), Ok(()));
+        assert_eq!(bucket.take(1.0, start), Err(Duration::from_millis(250)));
+        assert_eq!(bucket.take(1.0, ms(250)), Ok(()));
+        // It never holds more than its capacity, however long it's left:
+        assert!(bucket.is_full(ms(10_000)));
+        assert_eq!(bucket.take(2.5, ms(10_000)), Ok(()));
+        assert_eq!(bucket.take(1.0, ms(10_000)), Err(Duration::from_millis(375)));
+    }
+
+    #[test]
+    fn test_client_limiter() {
+        let start = Instant::now();
+        let limits = Limits { messages_per_sec: 2, bytes_per_sec: 10, ..Limits::default() };
+        let mut limiter = ClientLimiter::new(&limits, start);
+        // A burst's worth of small messages gets through:
+        for _ in 0..10 {
+            assert_eq!(limiter.check(1, start), Verdict::Allow);
+        }
+        assert_eq!(limiter.check(1, start), Verdict::SlowDown(Duration::from_millis(500)));
+
+        // A message bigger than the byte rate allows for still gets through eventually, but then
+        // has to be paid off:
+        let later = start + Duration::from_secs(100);
+        assert_eq!(limiter.check(100, later), Verdict::Allow);
+        assert_eq!(limiter.check(1, later), Verdict::SlowDown(Duration::from_millis(5100)));
+
+        // Having used up the rest of its patience, there's no more slowing down:
+        for _ in 1..PATIENCE as usize {
+            assert!(matches!(limiter.check(1, later), Verdict::SlowDown(_)));
+        }
+        assert_eq!(limiter.check(1, later), Verdict::Disconnect);
+
+        let unlimited = Limits { messages_per_sec: 0, bytes_per_sec: 0, ..Limits::default() };
+        let mut limiter = ClientLimiter::new(&unlimited, start);
+        for _ in 0..1000 {
+            assert_eq!(limiter.check(1 << 20, start), Verdict::Allow);
+        }
+    }
+
+    #[test]
+    fn test_addr_limiter() {
+        let start = Instant::now();
+        let metrics = Arc::new(Metrics::new());
+        assert!(AddrLimiter::new(0, "connection", metrics.clone()).is_none());
+        let limiter = AddrLimiter::new(60, "connection", metrics).unwrap();
+        let (a, b) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        for _ in 0..60 {
+            assert_eq!(limiter.check(a, start), Ok(()));
+        }
+        assert_eq!(limiter.check(a, start), Err(Duration::from_secs(1)));
+        // Everyone gets their own allowance:
+        assert_eq!(limiter.check(b, start), Ok(()));
+        assert_eq!(limiter.check(a, start + Duration::from_secs(1)), Ok(()));
+    }
+}
@@ -1,58 +1,370 @@
 use {
+    crypto::{digest::Digest, sha2::Sha256},
     hyper::{
         Body,
         body::Bytes,
+        Method,
         Request,
         Response,
         StatusCode,
-        header,
+        header::{self, HeaderMap, HeaderValue},
         http,
     },
-    log::warn,
-    std::str,
+    log::{error, warn},
+    std::{fs, path::Path, str},
 };
 
 use {
-    crate::hyper_helpers::server_header,
-    macros::template,
+    crate::{
+        hyper_helpers::{header_list, server_header},
+        router::Params,
+    },
+    macros::{embed_asset, template},
 };
 
-pub fn handle_get(req: Request<Body>) -> Result<Response<Body>, http::Error> {
-    let b = Response::builder().header(header::SERVER, server_header());
-    let x = match req.uri().path() {
-        "/" | "/index.html" => {
-            if let Some(host) = req.headers().get("host") {
-                Some(("text/html", generate_client_html(host.as_bytes())))
-            } else {
-                warn!("Request missing host header!");
-                None
-            }
+// A file baked into the server at build time, with its ETag and compressed variants worked out
+// in advance by embed_asset!:
+pub struct Asset {
+    pub etag: &'static str,
+    pub identity: &'static [u8],
+    pub gzip: &'static [u8],
+    pub brotli: &'static [u8],
+}
+
+struct Representation {
+    content_type: &'static str,
+    cache_control: &'static str,
+    etag: Option<String>,
+    content_encoding: Option<&'static str>,
+    // Whether there are other encodings we might have chosen instead:
+    varies: bool,
+    body: Bytes,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Coding { Identity, Gzip, Brotli }
+
+// The files that make up a client build, as found in a static_dir:
+pub const CLIENT_ASSETS: [&str; 2] = ["wasm_hello_world.js", "wasm_hello_world_bg.wasm"];
+
+const CLIENT_JS: Asset = embed_asset!("../../client/static/wasm_hello_world.js");
+const CLIENT_WASM: Asset = embed_asset!("../../client/static/wasm_hello_world_bg.wasm");
+
+// The asset names aren't content-addressed, so browsers always have to check back with us, but
+// unchanged assets only cost them a 304. The HTML depends on the Host header, so isn't worth
+// caching at all, and neither are assets we're reading from disk in dev mode.
+const CACHE_EMBEDDED: &str = "public, no-cache";
+const CACHE_NEVER: &str = "no-store";
+
+pub fn index(req: Request<Body>, secure: bool) -> Result<Response<Body>, http::Error> {
+    match req.headers().get(header::HOST) {
+        Some(host) => {
+            let body = generate_client_html(host.as_bytes(), secure);
+            respond(&req, Representation {
+                content_type: "text/html",
+                cache_control: CACHE_NEVER,
+                etag: None,
+                content_encoding: None,
+                varies: false,
+                body,
+            })
         },
-        "/wasm_hello_world.js" => Some(("application/javascript", CLIENT_JS)),
-        "/wasm_hello_world_bg.wasm" => Some(("application/wasm", CLIENT_WASM)),
-        path => {
-            warn!("Requested missing path: {}", path);
+        None => {
+            warn!("Request missing host header!");
+            not_found()
+        }
+    }
+}
+
+// Serves the asset named by the route's "asset" parameter. If given a static_dir, client assets
+// are read from there on each request rather than using the copies baked in at compile time.
+pub fn client_asset(req: Request<Body>, static_dir: Option<&Path>) -> Result<Response<Body>, http::Error> {
+    // Ranges are of the encoded content, so we keep things simple by not compressing those:
+    let coding = if req.headers().contains_key(header::RANGE) {
+        Coding::Identity
+    } else {
+        negotiate_coding(req.headers())
+    };
+    let repr = match Params::get(&req, "asset") {
+        Some(name @ "wasm_hello_world.js") =>
+            asset_representation(static_dir, name, &CLIENT_JS, coding, "application/javascript"),
+        Some(name @ "wasm_hello_world_bg.wasm") =>
+            asset_representation(static_dir, name, &CLIENT_WASM, coding, "application/wasm"),
+        _ => {
+            warn!("Requested missing path: {}", req.uri().path());
             None
         }
     };
-    match x {
-        None => b.status(StatusCode::NOT_FOUND).body(Body::empty()),
-        Some((content_type, bytes)) => b
-            .header(header::CONTENT_TYPE, content_type)
-            .body(Body::from(bytes)),
+    match repr {
+        None => not_found(),
+        Some(repr) => respond(&req, repr),
+    }
+}
+
+fn not_found() -> Result<Response<Body>, http::Error> {
+    Response::builder()
+        .header(header::SERVER, server_header())
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::empty())
+}
+
+fn respond(req: &Request<Body>, repr: Representation) -> Result<Response<Body>, http::Error> {
+    let mut b = Response::builder()
+        .header(header::SERVER, server_header())
+        .header(header::CONTENT_TYPE, repr.content_type)
+        .header(header::CACHE_CONTROL, repr.cache_control)
+        .header(header::ACCEPT_RANGES, "bytes");
+    if let Some(etag) = &repr.etag {
+        b = b.header(header::ETAG, etag);
+    }
+    if let Some(encoding) = repr.content_encoding {
+        b = b.header(header::CONTENT_ENCODING, encoding);
+    }
+    if repr.varies {
+        b = b.header(header::VARY, "Accept-Encoding");
+    }
+
+    let etag = repr.etag.as_deref();
+    if let Some(etag) = etag {
+        let if_none_match = header_list(req.headers(), header::IF_NONE_MATCH);
+        if if_none_match.iter().any(|t| *t == "*" || weak_eq(t, etag)) {
+            return b.status(StatusCode::NOT_MODIFIED).body(Body::empty());
+        }
+    }
+
+    let len = repr.body.len();
+    // If-Range lets a client say "only give me part of it if it's still the version I've
+    // partly got":
+    let range_valid = match req.headers().get(header::IF_RANGE) {
+        None => true,
+        Some(if_range) => etag.is_some_and(|etag| if_range.as_bytes() == etag.as_bytes()),
+    };
+    let range = req.headers().get(header::RANGE)
+        .filter(|_| range_valid)
+        .and_then(|r| r.to_str().ok())
+        .and_then(|r| parse_range(r, len));
+    let (b, body) = match range {
+        None => (b.status(StatusCode::OK), repr.body),
+        Some(Err(())) => {
+            return b.status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+                .body(Body::empty());
+        },
+        Some(Ok((start, end))) => (
+            b.status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len)),
+            repr.body.slice(start..=end)
+        ),
+    };
+    let b = b.header(header::CONTENT_LENGTH, body.len());
+    if req.method() == Method::HEAD {
+        b.body(Body::empty())
+    } else {
+        b.body(Body::from(body))
     }
 }
 
-fn generate_client_html(host: &[u8]) -> Bytes {
+fn asset_representation(
+    static_dir: Option<&Path>, name: &str, embedded: &Asset, coding: Coding, content_type: &'static str
+) -> Option<Representation> {
+    match static_dir {
+        None => {
+            let (body, content_encoding, suffix) = match coding {
+                Coding::Identity => (embedded.identity, None, ""),
+                Coding::Gzip => (embedded.gzip, Some("gzip"), "-gzip"),
+                Coding::Brotli => (embedded.brotli, Some("br"), "-br"),
+            };
+            // Each encoding is a different sequence of bytes, so needs its own strong ETag:
+            let etag = format!("{}{}\"", &embedded.etag[..embedded.etag.len() - 1], suffix);
+            Some(Representation {
+                content_type,
+                cache_control: CACHE_EMBEDDED,
+                etag: Some(etag),
+                content_encoding,
+                varies: true,
+                body: Bytes::from_static(body),
+            })
+        },
+        Some(dir) => match fs::read(dir.join(name)) {
+            Ok(b) => Some(Representation {
+                content_type,
+                cache_control: CACHE_NEVER,
+                etag: Some(content_etag(&b)),
+                content_encoding: None,
+                varies: false,
+                body: Bytes::from(b),
+            }),
+            Err(e) => {
+                error!("Failed to read {} from {}: {}", name, dir.display(), e);
+                None
+            }
+        }
+    }
+}
+
+// Matches the ETags that embed_asset! generates at build time:
+fn content_etag(content: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(content);
+    format!("\"{}\"", &hasher.result_str()[..32])
+}
+
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+// We have a preference for brotli, as it's smaller, but will settle for whatever the client
+// will accept:
+fn negotiate_coding(headers: &HeaderMap<HeaderValue>) -> Coding {
+    let accepted: Vec<(&str, bool)> = header_list(headers, header::ACCEPT_ENCODING).into_iter()
+        .map(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let coding = parts.next().unwrap_or("");
+            let q = parts.filter_map(|p| p.strip_prefix("q="))
+                .filter_map(|q| q.parse::<f32>().ok())
+                .next()
+                .unwrap_or(1.0);
+            (coding, q > 0.0)
+        })
+        .collect();
+    let accepts = |coding: &str| accepted.iter()
+        .find(|(c, _)| c.eq_ignore_ascii_case(coding))
+        .or_else(|| accepted.iter().find(|(c, _)| *c == "*"))
+        .is_some_and(|(_, ok)| *ok);
+    if accepts("br") {
+        Coding::Brotli
+    } else if accepts("gzip") {
+        Coding::Gzip
+    } else {
+        Coding::Identity
+    }
+}
+
+// Handles a single "bytes=" range, giving the inclusive start and end indices. Returns None for
+// anything we don't understand (including multiple ranges), in which case we'll just send
+// everything, and an error for ranges that are well formed but lie outside the content.
+fn parse_range(header: &str, len: usize) -> Option<Result<(usize, usize), ()>> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') { return None }
+    let mut parts = spec.splitn(2, '-').map(str::trim);
+    let (start, end) = (parts.next()?, parts.next()?);
+    let range = match (start, end) {
+        ("", "") => return None,
+        ("", suffix) => {
+            let suffix: usize = suffix.parse().ok()?;
+            if suffix == 0 || len == 0 { return Some(Err(())) }
+            (len.saturating_sub(suffix), len - 1)
+        },
+        (start, end) => {
+            let start: usize = start.parse().ok()?;
+            let end = if end.is_empty() { usize::MAX } else { end.parse().ok()? };
+            if end < start { return None }
+            if start >= len { return Some(Err(())) }
+            (start, end.min(len - 1))
+        },
+    };
+    Some(Ok(range))
+}
+
+fn generate_client_html(host: &[u8], secure: bool) -> Bytes {
     Bytes::from(template!(
         "../templates/index.html.template",
+        if secure { "wss" } else { "ws" },
         str::from_utf8(host).unwrap()
     ))
 }
 
-const CLIENT_JS: Bytes = Bytes::from_static(
-    include_bytes!("../../client/static/wasm_hello_world.js")
-);
-const CLIENT_WASM: Bytes = Bytes::from_static(
-    include_bytes!("../../client/static/wasm_hello_world_bg.wasm")
-);
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        hyper::body::to_bytes,
+    };
+
+    fn headers(pairs: &[(header::HeaderName, &'static str)]) -> HeaderMap<HeaderValue> {
+        pairs.iter().map(|(k, v)| (k.clone(), HeaderValue::from_static(v))).collect()
+    }
+
+    #[test]
+    fn test_negotiate_coding() {
+        let ae = |v| negotiate_coding(&headers(&[(header::ACCEPT_ENCODING, v)]));
+        assert_eq!(negotiate_coding(&HeaderMap::new()), Coding::Identity);
+        assert_eq!(ae("gzip, deflate, br"), Coding::Brotli);
+        assert_eq!(ae("gzip;q=0.5, br;q=0"), Coding::Gzip);
+        assert_eq!(ae("*"), Coding::Brotli);
+        assert_eq!(ae("*;q=0, identity"), Coding::Identity);
+        assert_eq!(ae("deflate"), Coding::Identity);
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-3", 10), Some(Ok((0, 3))));
+        assert_eq!(parse_range("bytes=5-", 10), Some(Ok((5, 9))));
+        assert_eq!(parse_range("bytes=5-100", 10), Some(Ok((5, 9))));
+        assert_eq!(parse_range("bytes=-3", 10), Some(Ok((7, 9))));
+        assert_eq!(parse_range("bytes=-30", 10), Some(Ok((0, 9))));
+        assert_eq!(parse_range("bytes=10-", 10), Some(Err(())));
+        assert_eq!(parse_range("bytes=-0", 10), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-1,4-5", 10), None);
+        assert_eq!(parse_range("bytes=3-1", 10), None);
+        assert_eq!(parse_range("lines=0-1", 10), None);
+    }
+
+    fn repr() -> Representation {
+        Representation {
+            content_type: "text/plain",
+            cache_control: CACHE_EMBEDDED,
+            etag: Some(content_etag(b"0123456789")),
+            content_encoding: None,
+            varies: false,
+            body: Bytes::from_static(b"0123456789"),
+        }
+    }
+
+    async fn get(method: Method, pairs: &[(header::HeaderName, &'static str)]) -> (StatusCode, HeaderMap, Bytes) {
+        let mut req = Request::builder().method(method).body(Body::empty()).unwrap();
+        *req.headers_mut() = headers(pairs);
+        let resp = respond(&req, repr()).unwrap();
+        let (parts, body) = resp.into_parts();
+        (parts.status, parts.headers, to_bytes(body).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_respond() {
+        let (status, headers, body) = get(Method::GET, &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_LENGTH], "10");
+        assert_eq!(body, "0123456789");
+        let etag = headers[header::ETAG].to_str().unwrap().to_string();
+
+        let (status, headers, body) = get(Method::HEAD, &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_LENGTH], "10");
+        assert!(body.is_empty());
+
+        let etag: &'static str = Box::leak(etag.into_boxed_str());
+        let (status, _, body) = get(Method::GET, &[(header::IF_NONE_MATCH, etag)]).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert!(body.is_empty());
+        let (status, _, _) = get(Method::GET, &[(header::IF_NONE_MATCH, "\"nope\"")]).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_respond_range() {
+        let (status, headers, body) = get(Method::GET, &[(header::RANGE, "bytes=2-4")]).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(headers[header::CONTENT_RANGE], "bytes 2-4/10");
+        assert_eq!(body, "234");
+
+        let (status, headers, _) = get(Method::GET, &[(header::RANGE, "bytes=20-")]).await;
+        assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(headers[header::CONTENT_RANGE], "bytes */10");
+
+        let (status, _, body) = get(Method::GET, &[
+            (header::RANGE, "bytes=2-4"), (header::IF_RANGE, "\"stale\"")
+        ]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "0123456789");
+    }
+}
@@ -0,0 +1,426 @@
+use {
+    log::error,
+    std::{
+        collections::{BTreeMap, BTreeSet, VecDeque},
+        time::SystemTime,
+    },
+};
+
+use {
+    common::clapi::{ClientId, Grantee, HistoryEntry, ProtocolError, Role, RoomInfo, RoomName, Seq},
+    crate::{
+        config::History,
+        storage::{Storage, StorageError, DEFAULT_ROLE},
+    },
+};
+
+// Rooms are created when someone first joins them and stick around once they're empty, until
+// somebody explicitly destroys them. They and their history are written through to storage as
+// they change, so survive restarts. Whoever creates a room owns it, and it's up to them who else
+// gets to do what in it.
+pub struct Rooms {
+    rooms: BTreeMap<RoomName, Room>,
+    history_limits: History,
+    storage: Box<dyn Storage>,
+}
+
+pub struct Room {
+    pub members: BTreeSet<ClientId>,
+    // Oldest first, along with when each was sent. Wall clock times, unlike Instants, still mean
+    // something after a restart:
+    history: VecDeque<(SystemTime, HistoryEntry)>,
+    next_seq: Seq,
+    roles: BTreeMap<Grantee, Role>,
+    default_role: Role,
+}
+
+pub struct Joined {
+    pub created: bool,
+    pub info: RoomInfo,
+    pub role: Role,
+}
+
+// Who's doing something to a room, as far as roles are concerned:
+#[derive(Clone, Copy, Debug)]
+pub struct Actor<'a> {
+    pub client_id: ClientId,
+    pub user: Option<&'a str>,
+    pub name: Option<&'a str>,
+}
+
+impl Actor<'_> {
+    // Users get roles as themselves, so that they keep them when they come back:
+    fn grantee(&self) -> Grantee {
+        match self.user {
+            Some(user) => Grantee::User(user.to_string()),
+            None => Grantee::Client(self.client_id),
+        }
+    }
+}
+
+impl Rooms {
+    pub fn load(history_limits: History, storage: Box<dyn Storage>) -> Result<Self, StorageError> {
+        let now = SystemTime::now();
+        let mut rooms = BTreeMap::new();
+        for stored in storage.load_rooms()? {
+            let roles = stored.roles.into_iter().map(|(user, role)| (Grantee::User(user), role)).collect();
+            let mut room = Room {
+                members: BTreeSet::new(),
+                history: stored.history.into_iter().collect(),
+                next_seq: stored.next_seq,
+                roles,
+                default_role: stored.default_role,
+            };
+            room.prune(&history_limits, now);
+            rooms.insert(stored.name, room);
+        }
+        Ok(Rooms { rooms, history_limits, storage })
+    }
+
+    pub fn flush(&self) {
+        persist(self.storage.flush());
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Room> {
+        self.rooms.get(name)
+    }
+
+    pub fn info(&self) -> Vec<RoomInfo> {
+        self.rooms.iter().map(|(name, room)| room.info(name)).collect()
+    }
+
+    pub fn join(&mut self, name: &str, actor: Actor) -> Joined {
+        let created = !self.rooms.contains_key(name);
+        if created {
+            persist(self.storage.create_room(name));
+            let mut room = Room::new();
+            room.roles.insert(actor.grantee(), Role::Owner);
+            if let Some(user) = actor.user {
+                persist(self.storage.set_role(name, user, Some(Role::Owner)));
+            }
+            self.rooms.insert(name.to_string(), room);
+        }
+        let room = self.rooms.get_mut(name).expect("room just created");
+        room.members.insert(actor.client_id);
+        Joined { created, info: room.info(name), role: room.role(actor) }
+    }
+
+    // Returns whether leaving emptied the room.
+    pub fn leave(&mut self, name: &str, client_id: ClientId) -> Result<bool, ProtocolError> {
+        let room = self.rooms.get_mut(name)
+            .ok_or_else(|| ProtocolError::NoSuchRoom { room: name.to_string() })?;
+        if !room.members.remove(&client_id) {
+            return Err(ProtocolError::NotInRoom { room: name.to_string() });
+        }
+        Ok(room.members.is_empty())
+    }
+
+    // Takes a client out of every room it's in, and forgets any roles it was granted, for when
+    // it's gone for good. Returns the names of any rooms that it was the last member of.
+    pub fn leave_all(&mut self, client_id: ClientId) -> Vec<RoomName> {
+        self.rooms.iter_mut()
+            .filter_map(|(name, room)| {
+                room.roles.remove(&Grantee::Client(client_id));
+                let emptied = room.members.remove(&client_id) && room.members.is_empty();
+                if emptied { Some(name.clone()) } else { None }
+            })
+            .collect()
+    }
+
+    // Numbers a broadcast and remembers it, if the sender's allowed to send it:
+    pub fn post(&mut self, name: &str, actor: Actor, text: String, now: SystemTime) -> Result<HistoryEntry, ProtocolError> {
+        let room = member_of(&mut self.rooms, name, actor.client_id)?;
+        room.require(name, actor, Role::Editor)?;
+        let sender = actor.user.or(actor.name).map(str::to_string);
+        let entry = HistoryEntry { seq: room.next_seq, from: actor.client_id, text, sender };
+        room.next_seq += 1;
+        room.history.push_back((now, entry.clone()));
+        persist(self.storage.append(name, now, &entry));
+        if room.prune(&self.history_limits, now) {
+            persist(self.storage.prune(name, room.oldest_seq()));
+        }
+        Ok(entry)
+    }
+
+    pub fn history(&mut self, name: &str, client_id: ClientId, since: Option<Seq>, now: SystemTime) -> Result<Vec<HistoryEntry>, ProtocolError> {
+        let room = member_of(&mut self.rooms, name, client_id)?;
+        if room.prune(&self.history_limits, now) {
+            persist(self.storage.prune(name, room.oldest_seq()));
+        }
+        Ok(room.history.iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| since.is_none_or(|since| entry.seq > since))
+            .cloned()
+            .collect())
+    }
+
+    // Rooms whose owners have all gone (which happens when they weren't logged in) are fair game:
+    pub fn destroy(&mut self, name: &str, actor: Actor) -> Result<(), ProtocolError> {
+        let room = find(&mut self.rooms, name)?;
+        if room.roles.values().any(|role| *role == Role::Owner) {
+            room.require(name, actor, Role::Owner)?;
+        }
+        if !room.members.is_empty() {
+            return Err(ProtocolError::RoomNotEmpty { room: name.to_string() });
+        }
+        self.rooms.remove(name);
+        persist(self.storage.destroy_room(name));
+        Ok(())
+    }
+
+    // A role of None revokes whatever the grantee had, leaving them with the room's default:
+    pub fn grant(&mut self, name: &str, actor: Actor, grantee: &Grantee, role: Option<Role>) -> Result<(), ProtocolError> {
+        let room = find(&mut self.rooms, name)?;
+        room.require(name, actor, Role::Owner)?;
+        match role {
+            Some(role) => room.roles.insert(grantee.clone(), role),
+            None => room.roles.remove(grantee),
+        };
+        if let Grantee::User(user) = grantee {
+            persist(self.storage.set_role(name, user, role));
+        }
+        Ok(())
+    }
+
+    pub fn set_default_role(&mut self, name: &str, actor: Actor, role: Role) -> Result<(), ProtocolError> {
+        let room = find(&mut self.rooms, name)?;
+        room.require(name, actor, Role::Owner)?;
+        room.default_role = role;
+        persist(self.storage.set_default_role(name, role));
+        Ok(())
+    }
+}
+
+impl Room {
+    fn new() -> Self {
+        Room {
+            members: BTreeSet::new(),
+            history: VecDeque::new(),
+            next_seq: 0,
+            roles: BTreeMap::new(),
+            default_role: DEFAULT_ROLE,
+        }
+    }
+
+    pub fn is_member(&self, client_id: ClientId) -> bool {
+        self.members.contains(&client_id)
+    }
+
+    // Whichever's the greater of what the client and its user have been granted, or the default
+    // if neither has been granted anything. So a viewer's grant can take away what the default
+    // would have given:
+    pub fn role(&self, actor: Actor) -> Role {
+        let user = actor.user.and_then(|user| self.roles.get(&Grantee::User(user.to_string())));
+        let client = self.roles.get(&Grantee::Client(actor.client_id));
+        user.max(client).copied().unwrap_or(self.default_role)
+    }
+
+    fn require(&self, name: &str, actor: Actor, needs: Role) -> Result<(), ProtocolError> {
+        if self.role(actor) < needs {
+            return Err(ProtocolError::Forbidden { room: name.to_string(), needs });
+        }
+        Ok(())
+    }
+
+    fn info(&self, name: &str) -> RoomInfo {
+        RoomInfo { name: name.to_string(), members: self.members.iter().copied().collect() }
+    }
+
+    // Returns whether there was anything to prune:
+    fn prune(&mut self, limits: &History, now: SystemTime) -> bool {
+        let before = self.history.len();
+        while self.history.len() > limits.max_messages {
+            self.history.pop_front();
+        }
+        while let Some((sent, _)) = self.history.front() {
+            // Anything from the future (the clock must have gone back) counts as brand new:
+            if now.duration_since(*sent).unwrap_or_default() <= limits.max_age { break }
+            self.history.pop_front();
+        }
+        self.history.len() != before
+    }
+
+    fn oldest_seq(&self) -> Seq {
+        self.history.front().map_or(self.next_seq, |(_, entry)| entry.seq)
+    }
+}
+
+fn find<'a>(rooms: &'a mut BTreeMap<RoomName, Room>, name: &str) -> Result<&'a mut Room, ProtocolError> {
+    rooms.get_mut(name).ok_or_else(|| ProtocolError::NoSuchRoom { room: name.to_string() })
+}
+
+fn member_of<'a>(rooms: &'a mut BTreeMap<RoomName, Room>, name: &str, client_id: ClientId) -> Result<&'a mut Room, ProtocolError> {
+    let room = find(rooms, name)?;
+    if !room.is_member(client_id) {
+        return Err(ProtocolError::NotInRoom { room: name.to_string() });
+    }
+    Ok(room)
+}
+
+// Storage going wrong shouldn't stop anyone talking, so we just complain about it:
+fn persist(result: Result<(), StorageError>) {
+    if let Err(e) = result {
+        error!("Storage error: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::storage::{MemoryStorage, SledStorage},
+        std::time::Duration,
+    };
+
+    fn rooms(history_limits: History) -> Rooms {
+        Rooms::load(history_limits, Box::new(MemoryStorage::default())).unwrap()
+    }
+
+    fn anon(client_id: ClientId) -> Actor<'static> {
+        Actor { client_id, user: None, name: None }
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let mut rooms = rooms(History::default());
+        let joined = rooms.join("a", anon(1));
+        assert!(joined.created);
+        assert_eq!(joined.info.members, vec![1]);
+        let joined = rooms.join("a", anon(2));
+        assert!(!joined.created);
+        assert_eq!(joined.info.members, vec![1, 2]);
+
+        assert_eq!(rooms.leave("a", 1), Ok(false));
+        assert_eq!(rooms.leave("a", 1), Err(ProtocolError::NotInRoom { room: "a".to_string() }));
+        assert_eq!(rooms.destroy("a", anon(1)), Err(ProtocolError::RoomNotEmpty { room: "a".to_string() }));
+        assert_eq!(rooms.leave("a", 2), Ok(true));
+        assert!(rooms.get("a").is_some());
+        assert_eq!(rooms.destroy("a", anon(1)), Ok(()));
+        assert!(rooms.get("a").is_none());
+        assert_eq!(rooms.leave("a", 2), Err(ProtocolError::NoSuchRoom { room: "a".to_string() }));
+    }
+
+    #[test]
+    fn test_leave_all() {
+        let mut rooms = rooms(History::default());
+        rooms.join("a", anon(1));
+        rooms.join("b", anon(1));
+        rooms.join("b", anon(2));
+        rooms.join("c", anon(2));
+        assert_eq!(rooms.leave_all(1), vec!["a".to_string()]);
+        assert_eq!(
+            rooms.info(),
+            vec![
+                RoomInfo { name: "a".to_string(), members: vec![] },
+                RoomInfo { name: "b".to_string(), members: vec![2] },
+                RoomInfo { name: "c".to_string(), members: vec![2] },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_history() {
+        let mut rooms = rooms(History { max_messages: 3, max_age: Duration::from_secs(60) });
+        let start = SystemTime::now();
+        let secs = |n| start + Duration::from_secs(n);
+        rooms.join("a", anon(1));
+        assert_eq!(
+            rooms.post("a", anon(2), "hi".to_string(), start),
+            Err(ProtocolError::NotInRoom { room: "a".to_string() })
+        );
+        for (i, text) in ["one", "two", "three", "four"].iter().enumerate() {
+            let entry = rooms.post("a", anon(1), text.to_string(), secs(i as u64 * 10)).unwrap();
+            assert_eq!(entry.seq, i as Seq);
+        }
+        let seqs = |entries: Vec<HistoryEntry>| entries.iter().map(|e| e.seq).collect::<Vec<_>>();
+        // Only room for three:
+        assert_eq!(seqs(rooms.history("a", 1, None, secs(30)).unwrap()), vec![1, 2, 3]);
+        assert_eq!(seqs(rooms.history("a", 1, Some(2), secs(30)).unwrap()), vec![3]);
+        // And the second has got too old:
+        assert_eq!(seqs(rooms.history("a", 1, None, secs(71)).unwrap()), vec![2, 3]);
+        assert_eq!(
+            rooms.history("b", 1, None, start),
+            Err(ProtocolError::NoSuchRoom { room: "b".to_string() })
+        );
+    }
+
+    #[test]
+    fn test_old_history() {
+        let year = Duration::from_secs(365 * 24 * 60 * 60);
+        // Longer ago than this process, or likely even the machine, has been running:
+        let sent = SystemTime::now() - year;
+        let mut storage = MemoryStorage::default();
+        storage.create_room("a").unwrap();
+        storage.append("a", sent, &HistoryEntry { seq: 0, from: 1, text: "hi".to_string(), sender: None }).unwrap();
+        let mut rooms = Rooms::load(History { max_messages: 10, max_age: 2 * year }, Box::new(storage)).unwrap();
+        rooms.join("a", anon(1));
+        assert_eq!(rooms.history("a", 1, None, SystemTime::now()).unwrap().len(), 1);
+        // It does still get too old eventually:
+        assert_eq!(rooms.history("a", 1, None, sent + 3 * year).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_roles() {
+        let mut rooms = rooms(History::default());
+        let paul = Actor { client_id: 1, user: Some("paul"), name: None };
+        let forbidden = |needs| ProtocolError::Forbidden { room: "a".to_string(), needs };
+        assert_eq!(rooms.join("a", paul).role, Role::Owner);
+        assert_eq!(rooms.join("a", anon(2)).role, Role::Editor);
+        assert!(rooms.post("a", anon(2), "hi".to_string(), SystemTime::now()).is_ok());
+        assert_eq!(rooms.set_default_role("a", anon(2), Role::Viewer), Err(forbidden(Role::Owner)));
+        assert_eq!(rooms.grant("a", anon(2), &Grantee::Client(2), Some(Role::Owner)), Err(forbidden(Role::Owner)));
+
+        rooms.set_default_role("a", paul, Role::Viewer).unwrap();
+        assert_eq!(rooms.post("a", anon(2), "hi".to_string(), SystemTime::now()), Err(forbidden(Role::Editor)));
+        rooms.grant("a", paul, &Grantee::Client(2), Some(Role::Editor)).unwrap();
+        assert!(rooms.post("a", anon(2), "hi".to_string(), SystemTime::now()).is_ok());
+        // Roles go with the user, whichever client they're on:
+        let pete = Actor { client_id: 3, user: Some("pete"), name: None };
+        rooms.grant("a", paul, &Grantee::User("pete".to_string()), Some(Role::Owner)).unwrap();
+        assert_eq!(rooms.get("a").unwrap().role(pete), Role::Owner);
+        rooms.grant("a", pete, &Grantee::User("pete".to_string()), None).unwrap();
+        assert_eq!(rooms.get("a").unwrap().role(pete), Role::Viewer);
+
+        // Client grants go with the client:
+        rooms.leave_all(2);
+        assert_eq!(rooms.get("a").unwrap().role(anon(2)), Role::Viewer);
+        assert_eq!(rooms.destroy("a", anon(2)), Err(forbidden(Role::Owner)));
+        rooms.leave_all(1);
+        assert_eq!(rooms.destroy("a", paul), Ok(()));
+    }
+
+    #[test]
+    fn test_restart() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let limits = History { max_messages: 2, max_age: Duration::from_secs(60) };
+        let load = || Rooms::load(limits.clone(), Box::new(SledStorage::from_db(db.clone()).unwrap())).unwrap();
+        let paul = |client_id| Actor { client_id, user: Some("paul"), name: None };
+        {
+            let mut rooms = load();
+            rooms.join("a", paul(1));
+            rooms.join("b", anon(1));
+            for text in ["one", "two", "three"].iter() {
+                rooms.post("a", paul(1), text.to_string(), SystemTime::now()).unwrap();
+            }
+            rooms.destroy("b", anon(1)).unwrap_err();
+            rooms.leave("b", 1).unwrap();
+            rooms.destroy("b", anon(1)).unwrap();
+            rooms.flush();
+        }
+        let mut rooms = load();
+        // Everyone's gone, but what they said is still there, as are the roles of anyone who was
+        // logged in:
+        assert_eq!(rooms.info(), vec![RoomInfo { name: "a".to_string(), members: vec![] }]);
+        rooms.join("a", anon(2));
+        let said: Vec<(String, Option<String>)> = rooms.history("a", 2, None, SystemTime::now()).unwrap()
+            .into_iter().map(|e| (e.text, e.sender)).collect();
+        // Whoever has client id 1 now, it's still clear who said what:
+        let paul_said = |text: &str| (text.to_string(), Some("paul".to_string()));
+        assert_eq!(said, vec![paul_said("two"), paul_said("three")]);
+        let pete = Actor { client_id: 2, user: None, name: Some("pete") };
+        let entry = rooms.post("a", pete, "four".to_string(), SystemTime::now()).unwrap();
+        assert_eq!((entry.seq, entry.sender), (3, Some("pete".to_string())));
+        assert_eq!(rooms.get("a").unwrap().role(anon(2)), Role::Editor);
+        assert_eq!(rooms.get("a").unwrap().role(paul(5)), Role::Owner);
+    }
+}
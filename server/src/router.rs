@@ -0,0 +1,274 @@
+use {
+    futures::future::{BoxFuture, FutureExt, ready},
+    hyper::{
+        Body,
+        Method,
+        Request,
+        Response,
+        StatusCode,
+        header,
+        http,
+    },
+    std::{collections::BTreeMap, future::Future, sync::Arc},
+};
+
+use crate::hyper_helpers::server_header;
+
+pub type HandlerResult = Result<Response<Body>, http::Error>;
+
+// Handlers get given some shared context (C) alongside the request, so that they don't all have
+// to capture their own copies of it. They get their own copy of the context, as the futures they
+// return can't borrow from anything:
+pub type Handler<C> = Arc<dyn Fn(Request<Body>, C) -> BoxFuture<'static, HandlerResult> + Send + Sync>;
+
+// Middleware wraps a handler to make a new one, which can look at (or alter) the request before
+// passing it on, the response after, or not bother calling the inner handler at all:
+pub trait Middleware<C>: Fn(Handler<C>) -> Handler<C> {}
+impl<C, M: Fn(Handler<C>) -> Handler<C>> Middleware<C> for M {}
+
+type Guard = Box<dyn Fn(&Request<Body>) -> bool + Send + Sync>;
+
+pub struct Router<C> {
+    routes: Vec<Route<C>>,
+}
+
+pub struct Route<C> {
+    methods: Vec<Method>,
+    name: Arc<str>,
+    pattern: Pattern,
+    guard: Option<Guard>,
+    handler: Handler<C>,
+}
+
+// The parameters captured from the path by whichever route matched, stashed in the request's
+// extensions for the handler to pick out:
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Params(BTreeMap<String, String>);
+
+// The pattern of the route that matched, also put in the request's extensions, for things that
+// want to tell requests apart by route rather than by exact path:
+#[derive(Clone, Debug)]
+pub struct MatchedRoute(pub Arc<str>);
+
+impl Params {
+    pub fn get<'a>(req: &'a Request<Body>, name: &str) -> Option<&'a str> {
+        req.extensions().get::<Params>().and_then(|p| p.0.get(name)).map(String::as_str)
+    }
+}
+
+impl<C: 'static> Router<C> {
+    pub fn new() -> Self {
+        Router { routes: Vec::new() }
+    }
+
+    // Routes are tried in the order they're added, so more specific patterns need adding before
+    // any that overlap with them. GET routes also answer HEAD requests, as hyper takes care of
+    // not sending the body.
+    pub fn add<F, Fut>(&mut self, methods: &[Method], pattern: &str, f: F) -> &mut Route<C>
+        where F: Fn(Request<Body>, C) -> Fut + Send + Sync + 'static,
+              Fut: Future<Output = HandlerResult> + Send + 'static
+    {
+        let mut methods = methods.to_vec();
+        if methods.contains(&Method::GET) && !methods.contains(&Method::HEAD) {
+            methods.push(Method::HEAD);
+        }
+        self.routes.push(Route {
+            methods,
+            name: pattern.into(),
+            pattern: Pattern::parse(pattern),
+            guard: None,
+            handler: Arc::new(move |req, ctx| f(req, ctx).boxed()),
+        });
+        self.routes.last_mut().unwrap()
+    }
+
+    pub fn get<F, Fut>(&mut self, pattern: &str, f: F) -> &mut Route<C>
+        where F: Fn(Request<Body>, C) -> Fut + Send + Sync + 'static,
+              Fut: Future<Output = HandlerResult> + Send + 'static
+    {
+        self.add(&[Method::GET], pattern, f)
+    }
+
+    // Applies the middleware to every route added so far:
+    pub fn wrap<M: Middleware<C>>(&mut self, m: M) -> &mut Self {
+        for route in self.routes.iter_mut() {
+            route.wrap(&m);
+        }
+        self
+    }
+
+    pub fn handle(&self, mut req: Request<Body>, ctx: C) -> BoxFuture<'static, HandlerResult> {
+        let mut allowed = Vec::new();
+        for route in self.routes.iter() {
+            if let Some(params) = route.pattern.matches(req.uri().path()) {
+                if !route.guard.as_ref().is_none_or(|g| g(&req)) {
+                    continue
+                }
+                if route.methods.contains(req.method()) {
+                    req.extensions_mut().insert(params);
+                    req.extensions_mut().insert(MatchedRoute(route.name.clone()));
+                    return (route.handler)(req, ctx);
+                }
+                allowed.extend(route.methods.iter().cloned());
+            }
+        }
+        let resp = if allowed.is_empty() {
+            Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .header(header::SERVER, server_header())
+                .body(Body::empty())
+        } else {
+            allowed.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+            allowed.dedup();
+            let allow: Vec<&str> = allowed.iter().map(Method::as_str).collect();
+            Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .header(header::SERVER, server_header())
+                .header(header::ALLOW, allow.join(", "))
+                .body(Body::empty())
+        };
+        ready(resp).boxed()
+    }
+}
+
+impl<C> Route<C> {
+    // Only consider this route for requests that pass the given test, e.g. to tell websocket
+    // upgrades apart from ordinary requests for the same path:
+    pub fn guard<G: Fn(&Request<Body>) -> bool + Send + Sync + 'static>(&mut self, g: G) -> &mut Self {
+        self.guard = Some(Box::new(g));
+        self
+    }
+
+    pub fn wrap<M: Middleware<C>>(&mut self, m: M) -> &mut Self {
+        self.handler = m(self.handler.clone());
+        self
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    // Matches any single, non-empty path segment:
+    Param(String),
+    // Matches whatever's left of the path, which may be nothing:
+    Rest(String),
+}
+
+#[derive(Debug, PartialEq)]
+struct Pattern(Vec<Segment>);
+
+impl Pattern {
+    // Patterns look like "/rooms/:name/history" or "/static/*path":
+    fn parse(s: &str) -> Self {
+        Pattern(s.trim_start_matches('/').split('/').map(|seg| {
+            if let Some(name) = seg.strip_prefix(':') {
+                Segment::Param(name.to_string())
+            } else if let Some(name) = seg.strip_prefix('*') {
+                Segment::Rest(name.to_string())
+            } else {
+                Segment::Literal(seg.to_string())
+            }
+        }).collect())
+    }
+
+    fn matches(&self, path: &str) -> Option<Params> {
+        let mut params = BTreeMap::new();
+        let mut parts = path.trim_start_matches('/').split('/');
+        for seg in self.0.iter() {
+            match seg {
+                Segment::Literal(lit) => if parts.next()? != lit { return None },
+                Segment::Param(name) => {
+                    let part = parts.next().filter(|p| !p.is_empty())?;
+                    params.insert(name.clone(), part.to_string());
+                },
+                Segment::Rest(name) => {
+                    params.insert(name.clone(), parts.collect::<Vec<_>>().join("/"));
+                    return Some(Params(params));
+                },
+            }
+        }
+        match parts.next() {
+            None => Some(Params(params)),
+            Some(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        hyper::http::HeaderValue,
+    };
+
+    fn params(pairs: &[(&str, &str)]) -> Option<Params> {
+        Some(Params(pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()))
+    }
+
+    #[test]
+    fn test_pattern() {
+        let p = Pattern::parse("/");
+        assert_eq!(p.matches("/"), params(&[]));
+        assert_eq!(p.matches("/index.html"), None);
+
+        let p = Pattern::parse("/rooms/:name/history");
+        assert_eq!(p.matches("/rooms/lobby/history"), params(&[("name", "lobby")]));
+        assert_eq!(p.matches("/rooms//history"), None);
+        assert_eq!(p.matches("/rooms/lobby"), None);
+        assert_eq!(p.matches("/rooms/lobby/history/more"), None);
+
+        let p = Pattern::parse("/static/*path");
+        assert_eq!(p.matches("/static/a/b.js"), params(&[("path", "a/b.js")]));
+        assert_eq!(p.matches("/static"), params(&[("path", "")]));
+        assert_eq!(p.matches("/other/a.js"), None);
+    }
+
+    async fn echo_param(req: Request<Body>, prefix: &'static str) -> HandlerResult {
+        let name = Params::get(&req, "name").unwrap_or("-").to_string();
+        Response::builder().body(Body::from(format!("{}{}", prefix, name)))
+    }
+
+    fn request(method: Method, path: &str) -> Request<Body> {
+        Request::builder().method(method).uri(path).body(Body::empty()).unwrap()
+    }
+
+    async fn body(resp: Response<Body>) -> String {
+        String::from_utf8(hyper::body::to_bytes(resp.into_body()).await.unwrap().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_router() {
+        let mut router: Router<&'static str> = Router::new();
+        router.get("/", |_, _| ready(Response::builder().body(Body::from("ws"))))
+            .guard(|req| req.headers().contains_key(header::UPGRADE));
+        router.get("/", |_, _| ready(Response::builder().body(Body::from("index"))));
+        router.add(&[Method::PUT, Method::DELETE], "/rooms/:name", echo_param)
+            .wrap(|inner: Handler<&'static str>| -> Handler<&'static str> { Arc::new(move |req, ctx| {
+                let resp = inner(req, ctx);
+                async move {
+                    let mut resp = resp.await?;
+                    resp.headers_mut().insert("x-wrapped", HeaderValue::from_static("yes"));
+                    Ok(resp)
+                }.boxed()
+            })});
+        router.get("/rooms/:name", echo_param);
+
+        assert_eq!(body(router.handle(request(Method::GET, "/"), "").await.unwrap()).await, "index");
+        let mut upgrade = request(Method::GET, "/");
+        upgrade.headers_mut().insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+        assert_eq!(body(router.handle(upgrade, "").await.unwrap()).await, "ws");
+
+        let resp = router.handle(request(Method::DELETE, "/rooms/lobby"), "deleted ").await.unwrap();
+        assert_eq!(resp.headers()["x-wrapped"], "yes");
+        assert_eq!(body(resp).await, "deleted lobby");
+        let resp = router.handle(request(Method::HEAD, "/rooms/lobby"), "").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!resp.headers().contains_key("x-wrapped"));
+
+        let resp = router.handle(request(Method::POST, "/rooms/lobby"), "").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers()[header::ALLOW], "DELETE, GET, HEAD, PUT");
+        let resp = router.handle(request(Method::GET, "/nowhere"), "").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
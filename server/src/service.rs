@@ -1,32 +1,81 @@
 use {
     futures::{
-        future::{ok, ready, Ready},
+        future::{ok, Either, Ready},
         channel::mpsc,
     },
     hyper::{
-        Body, Request, Response,
+        Body, Request, Response, StatusCode,
+        header::{self, HeaderValue},
+        server::conn::AddrStream,
         service::Service,
     },
     std::{
         convert::Infallible,
+        future::Future,
+        net::IpAddr,
+        sync::Arc,
         task::{Context, Poll},
+        time::Duration,
     },
+    tokio::time::Instant,
 };
 
-pub struct ConnectionHandler<'a, Msg, F> {
+use crate::{hyper_helpers::server_header, ratelimit::AddrLimiter};
+
+// Connections that know who's on the other end, so that we can limit how often they connect:
+pub trait RemoteAddr {
+    fn remote_addr(&self) -> Option<IpAddr>;
+}
+
+impl RemoteAddr for AddrStream {
+    fn remote_addr(&self) -> Option<IpAddr> {
+        Some(AddrStream::remote_addr(self).ip())
+    }
+}
+
+// Put in each request's extensions, for handlers that want to know who they're talking to:
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RemoteIp(pub IpAddr);
+
+// hyper hands us references to its connections:
+impl<T: RemoteAddr> RemoteAddr for &T {
+    fn remote_addr(&self) -> Option<IpAddr> {
+        (*self).remote_addr()
+    }
+}
+
+pub struct ConnectionHandler<Msg, F> {
     tx: mpsc::UnboundedSender<Msg>,
-    f: &'a F
+    f: Arc<F>,
+    limiter: Option<Arc<AddrLimiter>>,
 }
 
-impl<'a, Msg, E, F: Fn(Request<Body>, mpsc::UnboundedSender<Msg>) -> Result<Response<Body>, E>> ConnectionHandler<'a, Msg, F> {
-    pub fn new(f: &'a F) -> (Self, mpsc::UnboundedReceiver<Msg>) {
+// Handlers are async, so that they can do things like read request bodies or ask the receiver
+// of our messages something and wait for the answer:
+impl<Msg, E, Fut, F> ConnectionHandler<Msg, F>
+    where F: Fn(Request<Body>, mpsc::UnboundedSender<Msg>) -> Fut,
+          Fut: Future<Output = Result<Response<Body>, E>>
+{
+    pub fn new(f: F, limiter: Option<AddrLimiter>) -> (Self, mpsc::UnboundedReceiver<Msg>) {
     let (tx, rx) = mpsc::unbounded();
-    (ConnectionHandler { tx, f }, rx)
+    (ConnectionHandler { tx, f: Arc::new(f), limiter: limiter.map(Arc::new) }, rx)
+    }
+
+    // For things other than HTTP requests that want to talk to the receiver:
+    pub fn sender(&self) -> mpsc::UnboundedSender<Msg> {
+        self.tx.clone()
     }
 }
 
-impl<'a, Conn, Msg, F> Service<Conn> for ConnectionHandler<'a, Msg, F> {
-    type Response = RequestHandler<'a, Msg, F>;
+// Derived Clone would needlessly require Msg and F to be Clone:
+impl<Msg, F> Clone for ConnectionHandler<Msg, F> {
+    fn clone(&self) -> Self {
+        ConnectionHandler { tx: self.tx.clone(), f: self.f.clone(), limiter: self.limiter.clone() }
+    }
+}
+
+impl<Conn: RemoteAddr, Msg, F> Service<Conn> for ConnectionHandler<Msg, F> {
+    type Response = RequestHandler<Msg, F>;
     type Error = Infallible;
     type Future = Ready<Result<Self::Response, Self::Error>>;
 
@@ -34,27 +83,56 @@ impl<'a, Conn, Msg, F> Service<Conn> for ConnectionHandler<'a, Msg, F> {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, _: Conn) -> Self::Future {
-        ok(RequestHandler { tx: self.tx.clone(), f: self.f })
+    fn call(&mut self, conn: Conn) -> Self::Future {
+        // hyper won't let us turn the connection away outright, so instead every request on it
+        // gets refused:
+        let remote_addr = conn.remote_addr();
+        let refused = match (&self.limiter, remote_addr) {
+            (Some(limiter), Some(addr)) => limiter.check(addr, Instant::now()).err(),
+            _ => None,
+        };
+        ok(RequestHandler { tx: self.tx.clone(), f: self.f.clone(), remote_addr, refused })
     }
 }
 
-pub struct RequestHandler<'a, Msg, F> {
+pub struct RequestHandler<Msg, F> {
     tx: mpsc::UnboundedSender<Msg>,
-    f: &'a F
+    f: Arc<F>,
+    remote_addr: Option<IpAddr>,
+    // With how long until the connection would have been allowed:
+    refused: Option<Duration>,
 }
 
-impl<Msg, E, F: Fn(Request<Body>, mpsc::UnboundedSender<Msg>) -> Result<Response<Body>, E>>
-Service<Request<Body>> for RequestHandler<'_, Msg, F> {
+impl<Msg, E, Fut, F> Service<Request<Body>> for RequestHandler<Msg, F>
+    where F: Fn(Request<Body>, mpsc::UnboundedSender<Msg>) -> Fut,
+          Fut: Future<Output = Result<Response<Body>, E>>
+{
     type Response = Response<Body>;
     type Error = E;
-    type Future = Ready<Result<Self::Response, Self::Error>>;
+    type Future = Either<Fut, Ready<Result<Response<Body>, E>>>;
 
     fn poll_ready(&mut self, _: &mut Context) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, req: Request<Body>) -> Self::Future {
-        ready((self.f)(req, self.tx.clone()))
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        if let Some(addr) = self.remote_addr {
+            req.extensions_mut().insert(RemoteIp(addr));
+        }
+        match self.refused {
+            None => Either::Left((self.f)(req, self.tx.clone())),
+            Some(wait) => Either::Right(ok(too_many_connections(wait))),
+        }
     }
 }
+
+fn too_many_connections(wait: Duration) -> Response<Body> {
+    let mut response = Response::new(Body::from("Too many connections, try again later\n"));
+    *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    let headers = response.headers_mut();
+    let secs = wait.as_secs() + (wait.subsec_nanos() > 0) as u64;
+    headers.insert(header::SERVER, HeaderValue::from_str(&server_header()).expect("bad server header"));
+    headers.insert(header::RETRY_AFTER, HeaderValue::from(secs));
+    headers.insert(header::CONNECTION, HeaderValue::from_static("close"));
+    response
+}
@@ -0,0 +1,501 @@
+use {
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::BTreeMap,
+        convert::TryInto,
+        fmt,
+        path::Path,
+        sync::Mutex,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
+};
+
+use common::clapi::{ClientId, HistoryEntry, Role, RoomName, Seq};
+
+// Whatever needs to survive a restart. Connected clients and room membership don't, as nobody
+// stays connected through a restart.
+pub trait Storage: Send {
+    fn load_rooms(&self) -> Result<Vec<StoredRoom>, StorageError>;
+    fn create_room(&mut self, room: &str) -> Result<(), StorageError>;
+    // Takes the room's history and roles with it:
+    fn destroy_room(&mut self, room: &str) -> Result<(), StorageError>;
+    fn append(&mut self, room: &str, sent: SystemTime, entry: &HistoryEntry) -> Result<(), StorageError>;
+    // Forgets everything in the room's history before the given message:
+    fn prune(&mut self, room: &str, oldest: Seq) -> Result<(), StorageError>;
+    // Only roles granted to users are worth keeping, as clients don't outlive a restart. None
+    // revokes the user's role:
+    fn set_role(&mut self, room: &str, user: &str, role: Option<Role>) -> Result<(), StorageError>;
+    fn set_default_role(&mut self, room: &str, role: Role) -> Result<(), StorageError>;
+    // Makes sure everything so far has actually hit the disk, for when we're about to exit:
+    fn flush(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
+
+// Accounts and who's logged in to them. Unlike rooms these get used straight from request
+// handlers, rather than going through the app, so they need to be shareable:
+pub trait AccountStorage: Send + Sync {
+    // Returns false, leaving the existing account alone, if the name's already taken:
+    fn create_account(&self, user: &str, password_hash: &str) -> Result<bool, StorageError>;
+    fn password_hash(&self, user: &str) -> Result<Option<String>, StorageError>;
+    fn create_login(&self, id: &str, login: &StoredLogin) -> Result<(), StorageError>;
+    fn login(&self, id: &str) -> Result<Option<StoredLogin>, StorageError>;
+    fn destroy_login(&self, id: &str) -> Result<(), StorageError>;
+    // Forgets every login that's expired by the given time:
+    fn prune_logins(&self, now: SystemTime) -> Result<(), StorageError>;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredRoom {
+    pub name: RoomName,
+    // So that sequence numbers carry on from where they were, even if the history's been pruned:
+    pub next_seq: Seq,
+    // Oldest first:
+    pub history: Vec<(SystemTime, HistoryEntry)>,
+    pub roles: BTreeMap<String, Role>,
+    pub default_role: Role,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredLogin {
+    pub user: String,
+    pub expires: SystemTime,
+}
+
+pub type Stores = (Box<dyn Storage>, Box<dyn AccountStorage>);
+
+// What rooms start out with, so that anyone can have their say unless the owners decide otherwise:
+pub const DEFAULT_ROLE: Role = Role::Editor;
+
+// Keeps everything on disk in the given directory if there is one, or just in memory (so not
+// really keeping it at all) if not.
+pub fn open(data_dir: Option<&Path>) -> Result<Stores, StorageError> {
+    Ok(match data_dir {
+        Some(dir) => {
+            let storage = SledStorage::open(dir)?;
+            (Box::new(storage.clone()), Box::new(storage))
+        },
+        None => (Box::new(MemoryStorage::default()), Box::new(MemoryAccounts::default())),
+    })
+}
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    rooms: BTreeMap<RoomName, StoredRoom>,
+}
+
+impl Storage for MemoryStorage {
+    fn load_rooms(&self) -> Result<Vec<StoredRoom>, StorageError> {
+        Ok(self.rooms.values().cloned().collect())
+    }
+
+    fn create_room(&mut self, room: &str) -> Result<(), StorageError> {
+        self.rooms.entry(room.to_string()).or_insert_with(|| StoredRoom {
+            name: room.to_string(),
+            next_seq: 0,
+            history: Vec::new(),
+            roles: BTreeMap::new(),
+            default_role: DEFAULT_ROLE,
+        });
+        Ok(())
+    }
+
+    fn destroy_room(&mut self, room: &str) -> Result<(), StorageError> {
+        self.rooms.remove(room);
+        Ok(())
+    }
+
+    fn append(&mut self, room: &str, sent: SystemTime, entry: &HistoryEntry) -> Result<(), StorageError> {
+        let stored = self.rooms.get_mut(room).ok_or_else(|| no_such_room(room))?;
+        stored.next_seq = entry.seq + 1;
+        stored.history.push((sent, entry.clone()));
+        Ok(())
+    }
+
+    fn prune(&mut self, room: &str, oldest: Seq) -> Result<(), StorageError> {
+        let stored = self.rooms.get_mut(room).ok_or_else(|| no_such_room(room))?;
+        stored.history.retain(|(_, entry)| entry.seq >= oldest);
+        Ok(())
+    }
+
+    fn set_role(&mut self, room: &str, user: &str, role: Option<Role>) -> Result<(), StorageError> {
+        let stored = self.rooms.get_mut(room).ok_or_else(|| no_such_room(room))?;
+        match role {
+            Some(role) => stored.roles.insert(user.to_string(), role),
+            None => stored.roles.remove(user),
+        };
+        Ok(())
+    }
+
+    fn set_default_role(&mut self, room: &str, role: Role) -> Result<(), StorageError> {
+        self.rooms.get_mut(room).ok_or_else(|| no_such_room(room))?.default_role = role;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryAccounts {
+    accounts: Mutex<BTreeMap<String, String>>,
+    logins: Mutex<BTreeMap<String, StoredLogin>>,
+}
+
+impl AccountStorage for MemoryAccounts {
+    fn create_account(&self, user: &str, password_hash: &str) -> Result<bool, StorageError> {
+        let mut accounts = self.accounts.lock().expect("poisoned");
+        if accounts.contains_key(user) {
+            return Ok(false);
+        }
+        accounts.insert(user.to_string(), password_hash.to_string());
+        Ok(true)
+    }
+
+    fn password_hash(&self, user: &str) -> Result<Option<String>, StorageError> {
+        Ok(self.accounts.lock().expect("poisoned").get(user).cloned())
+    }
+
+    fn create_login(&self, id: &str, login: &StoredLogin) -> Result<(), StorageError> {
+        self.logins.lock().expect("poisoned").insert(id.to_string(), login.clone());
+        Ok(())
+    }
+
+    fn login(&self, id: &str) -> Result<Option<StoredLogin>, StorageError> {
+        Ok(self.logins.lock().expect("poisoned").get(id).cloned())
+    }
+
+    fn destroy_login(&self, id: &str) -> Result<(), StorageError> {
+        self.logins.lock().expect("poisoned").remove(id);
+        Ok(())
+    }
+
+    fn prune_logins(&self, now: SystemTime) -> Result<(), StorageError> {
+        self.logins.lock().expect("poisoned").retain(|_, login| login.expires > now);
+        Ok(())
+    }
+}
+
+// Rooms go in one tree, keyed by name with their next sequence number as the value. History goes
+// in another, keyed by room and then sequence number, so that each room's history is contiguous
+// and in order. Roles are keyed the same way, but by user rather than sequence number, and each
+// room's default role goes in a tree of its own unless it's never been changed. Accounts and
+// logins get a tree each too, keyed by user name and login id.
+#[derive(Clone)]
+pub struct SledStorage {
+    db: sled::Db,
+    rooms: sled::Tree,
+    history: sled::Tree,
+    roles: sled::Tree,
+    default_roles: sled::Tree,
+    accounts: sled::Tree,
+    logins: sled::Tree,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredEntry {
+    sent_ms: u64,
+    from: ClientId,
+    text: String,
+    // Entries saved before we kept track of this don't have it:
+    #[serde(default)]
+    sender: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredLoginValue {
+    user: String,
+    expires_ms: u64,
+}
+
+impl SledStorage {
+    pub fn open(dir: &Path) -> Result<Self, StorageError> {
+        Self::from_db(sled::open(dir)?)
+    }
+
+    pub fn from_db(db: sled::Db) -> Result<Self, StorageError> {
+        let rooms = db.open_tree("rooms")?;
+        let history = db.open_tree("history")?;
+        let roles = db.open_tree("roles")?;
+        let default_roles = db.open_tree("default_roles")?;
+        let accounts = db.open_tree("accounts")?;
+        let logins = db.open_tree("logins")?;
+        Ok(SledStorage { db, rooms, history, roles, default_roles, accounts, logins })
+    }
+}
+
+impl Storage for SledStorage {
+    fn load_rooms(&self) -> Result<Vec<StoredRoom>, StorageError> {
+        self.rooms.iter().map(|kv| {
+            let (name, next_seq) = kv?;
+            let name = String::from_utf8(name.to_vec())
+                .map_err(|_| StorageError::new("room name isn't UTF-8"))?;
+            let history = self.history.scan_prefix(room_prefix(&name)).map(|kv| {
+                let (key, value) = kv?;
+                let stored: StoredEntry = serde_json::from_slice(&value)?;
+                let entry = HistoryEntry {
+                    seq: decode_seq(&key[key.len() - 8..])?, from: stored.from, text: stored.text, sender: stored.sender
+                };
+                Ok((UNIX_EPOCH + Duration::from_millis(stored.sent_ms), entry))
+            }).collect::<Result<_, StorageError>>()?;
+            let prefix = room_prefix(&name);
+            let roles = self.roles.scan_prefix(&prefix).map(|kv| {
+                let (key, value) = kv?;
+                let user = String::from_utf8(key[prefix.len()..].to_vec())
+                    .map_err(|_| StorageError::new("user name isn't UTF-8"))?;
+                Ok((user, serde_json::from_slice(&value)?))
+            }).collect::<Result<_, StorageError>>()?;
+            let default_role = match self.default_roles.get(&name)? {
+                Some(role) => serde_json::from_slice(&role)?,
+                None => DEFAULT_ROLE,
+            };
+            Ok(StoredRoom { next_seq: decode_seq(&next_seq)?, name, history, roles, default_role })
+        }).collect()
+    }
+
+    fn create_room(&mut self, room: &str) -> Result<(), StorageError> {
+        // Leaves any existing room alone:
+        let _ = self.rooms.compare_and_swap(room, None as Option<&[u8]>, Some(&0u64.to_be_bytes()))?;
+        Ok(())
+    }
+
+    fn destroy_room(&mut self, room: &str) -> Result<(), StorageError> {
+        let mut batch = sled::Batch::default();
+        for key in self.history.scan_prefix(room_prefix(room)).keys() {
+            batch.remove(key?);
+        }
+        self.history.apply_batch(batch)?;
+        let mut batch = sled::Batch::default();
+        for key in self.roles.scan_prefix(room_prefix(room)).keys() {
+            batch.remove(key?);
+        }
+        self.roles.apply_batch(batch)?;
+        self.default_roles.remove(room)?;
+        self.rooms.remove(room)?;
+        Ok(())
+    }
+
+    fn append(&mut self, room: &str, sent: SystemTime, entry: &HistoryEntry) -> Result<(), StorageError> {
+        let value = serde_json::to_vec(&StoredEntry {
+            sent_ms: unix_ms(sent), from: entry.from, text: entry.text.clone(), sender: entry.sender.clone()
+        })?;
+        self.history.insert(history_key(room, entry.seq), value)?;
+        self.rooms.insert(room, &(entry.seq + 1).to_be_bytes())?;
+        Ok(())
+    }
+
+    fn prune(&mut self, room: &str, oldest: Seq) -> Result<(), StorageError> {
+        let mut batch = sled::Batch::default();
+        for key in self.history.range(history_key(room, 0)..history_key(room, oldest)).keys() {
+            batch.remove(key?);
+        }
+        Ok(self.history.apply_batch(batch)?)
+    }
+
+    fn set_role(&mut self, room: &str, user: &str, role: Option<Role>) -> Result<(), StorageError> {
+        let mut key = room_prefix(room);
+        key.extend_from_slice(user.as_bytes());
+        match role {
+            Some(role) => self.roles.insert(key, serde_json::to_vec(&role)?)?,
+            None => self.roles.remove(key)?,
+        };
+        Ok(())
+    }
+
+    fn set_default_role(&mut self, room: &str, role: Role) -> Result<(), StorageError> {
+        self.default_roles.insert(room, serde_json::to_vec(&role)?)?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+impl AccountStorage for SledStorage {
+    fn create_account(&self, user: &str, password_hash: &str) -> Result<bool, StorageError> {
+        Ok(self.accounts.compare_and_swap(user, None as Option<&[u8]>, Some(password_hash))?.is_ok())
+    }
+
+    fn password_hash(&self, user: &str) -> Result<Option<String>, StorageError> {
+        self.accounts.get(user)?
+            .map(|hash| String::from_utf8(hash.to_vec()).map_err(|_| StorageError::new("password hash isn't UTF-8")))
+            .transpose()
+    }
+
+    fn create_login(&self, id: &str, login: &StoredLogin) -> Result<(), StorageError> {
+        let value = StoredLoginValue { user: login.user.clone(), expires_ms: unix_ms(login.expires) };
+        self.logins.insert(id, serde_json::to_vec(&value)?)?;
+        Ok(())
+    }
+
+    fn login(&self, id: &str) -> Result<Option<StoredLogin>, StorageError> {
+        self.logins.get(id)?.map(|value| {
+            let stored: StoredLoginValue = serde_json::from_slice(&value)?;
+            Ok(StoredLogin { user: stored.user, expires: UNIX_EPOCH + Duration::from_millis(stored.expires_ms) })
+        }).transpose()
+    }
+
+    fn destroy_login(&self, id: &str) -> Result<(), StorageError> {
+        self.logins.remove(id)?;
+        Ok(())
+    }
+
+    fn prune_logins(&self, now: SystemTime) -> Result<(), StorageError> {
+        let now_ms = unix_ms(now);
+        let mut batch = sled::Batch::default();
+        for kv in self.logins.iter() {
+            let (id, value) = kv?;
+            let stored: StoredLoginValue = serde_json::from_slice(&value)?;
+            if stored.expires_ms <= now_ms {
+                batch.remove(id);
+            }
+        }
+        Ok(self.logins.apply_batch(batch)?)
+    }
+}
+
+fn unix_ms(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+// Length prefixed, so that no room's prefix is a prefix of another's:
+fn room_prefix(room: &str) -> Vec<u8> {
+    let mut key = (room.len() as u32).to_be_bytes().to_vec();
+    key.extend_from_slice(room.as_bytes());
+    key
+}
+
+// Big-endian, so that keys sort in sequence order:
+fn history_key(room: &str, seq: Seq) -> Vec<u8> {
+    let mut key = room_prefix(room);
+    key.extend_from_slice(&seq.to_be_bytes());
+    key
+}
+
+fn decode_seq(bytes: &[u8]) -> Result<Seq, StorageError> {
+    bytes.try_into().map(Seq::from_be_bytes).map_err(|_| StorageError::new("bad sequence number"))
+}
+
+fn no_such_room(room: &str) -> StorageError {
+    StorageError(format!("no such room: {}", room))
+}
+
+#[derive(Debug)]
+pub struct StorageError(String);
+
+impl StorageError {
+    fn new(msg: &str) -> Self {
+        StorageError(msg.to_string())
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<sled::Error> for StorageError {
+    fn from(e: sled::Error) -> Self {
+        StorageError(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(e: serde_json::Error) -> Self {
+        StorageError(format!("bad stored value: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Both implementations should behave the same:
+    fn exercise(storage: &mut dyn Storage) {
+        let t = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let entry = |seq, text: &str| HistoryEntry { seq, from: 1, text: text.to_string(), sender: Some("paul".to_string()) };
+        storage.create_room("a").unwrap();
+        storage.create_room("ab").unwrap();
+        storage.create_room("gone").unwrap();
+        for (seq, text) in ["one", "two", "three"].iter().enumerate() {
+            storage.append("a", t(seq as u64), &entry(seq as Seq, text)).unwrap();
+        }
+        storage.append("ab", t(5), &entry(0, "other")).unwrap();
+        storage.append("gone", t(5), &entry(0, "bye")).unwrap();
+        storage.prune("a", 1).unwrap();
+        storage.set_role("a", "paul", Some(Role::Owner)).unwrap();
+        storage.set_role("a", "pete", Some(Role::Viewer)).unwrap();
+        storage.set_role("a", "pete", None).unwrap();
+        storage.set_role("ab", "pete", Some(Role::Editor)).unwrap();
+        storage.set_default_role("a", Role::Viewer).unwrap();
+        storage.set_role("gone", "paul", Some(Role::Owner)).unwrap();
+        storage.set_default_role("gone", Role::Viewer).unwrap();
+        storage.destroy_room("gone").unwrap();
+        // Creating a room that's already there mustn't wipe it:
+        storage.create_room("a").unwrap();
+        storage.flush().unwrap();
+
+        let roles = |roles: &[(&str, Role)]| roles.iter().map(|(u, r)| (u.to_string(), *r)).collect();
+        assert_eq!(storage.load_rooms().unwrap(), vec![
+            StoredRoom {
+                name: "a".to_string(),
+                next_seq: 3,
+                history: vec![(t(1), entry(1, "two")), (t(2), entry(2, "three"))],
+                roles: roles(&[("paul", Role::Owner)]),
+                default_role: Role::Viewer,
+            },
+            StoredRoom {
+                name: "ab".to_string(),
+                next_seq: 1,
+                history: vec![(t(5), entry(0, "other"))],
+                roles: roles(&[("pete", Role::Editor)]),
+                default_role: DEFAULT_ROLE,
+            },
+        ]);
+        // Nothing of the destroyed room should come back if it's recreated:
+        storage.create_room("gone").unwrap();
+        let gone = storage.load_rooms().unwrap().into_iter().find(|r| r.name == "gone").unwrap();
+        assert_eq!((gone.roles.len(), gone.default_role), (0, DEFAULT_ROLE));
+    }
+
+    fn exercise_accounts(storage: &dyn AccountStorage) {
+        assert!(storage.create_account("paul", "hash").unwrap());
+        assert!(!storage.create_account("paul", "other hash").unwrap());
+        assert_eq!(storage.password_hash("paul").unwrap(), Some("hash".to_string()));
+        assert_eq!(storage.password_hash("nobody").unwrap(), None);
+
+        let login = StoredLogin { user: "paul".to_string(), expires: UNIX_EPOCH + Duration::from_secs(5) };
+        storage.create_login("abc", &login).unwrap();
+        assert_eq!(storage.login("abc").unwrap(), Some(login));
+        storage.destroy_login("abc").unwrap();
+        assert_eq!(storage.login("abc").unwrap(), None);
+
+        let expiring = |secs| StoredLogin { user: "paul".to_string(), expires: UNIX_EPOCH + Duration::from_secs(secs) };
+        storage.create_login("old", &expiring(5)).unwrap();
+        storage.create_login("new", &expiring(10)).unwrap();
+        storage.prune_logins(UNIX_EPOCH + Duration::from_secs(5)).unwrap();
+        assert_eq!(storage.login("old").unwrap(), None);
+        assert_eq!(storage.login("new").unwrap(), Some(expiring(10)));
+    }
+
+    #[test]
+    fn test_memory() {
+        exercise(&mut MemoryStorage::default());
+        exercise_accounts(&MemoryAccounts::default());
+    }
+
+    #[test]
+    fn test_sled() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        exercise(&mut SledStorage::from_db(db.clone()).unwrap());
+        exercise_accounts(&SledStorage::from_db(db.clone()).unwrap());
+
+        // History saved before senders were kept still loads:
+        let storage = SledStorage::from_db(db).unwrap();
+        storage.history.insert(history_key("a", 3), br#"{"sent_ms":0,"from":1,"text":"old"}"#.to_vec()).unwrap();
+        let a = storage.load_rooms().unwrap().into_iter().find(|r| r.name == "a").unwrap();
+        assert_eq!(a.history.last().unwrap().1, HistoryEntry { seq: 3, from: 1, text: "old".to_string(), sender: None });
+    }
+}
@@ -0,0 +1,185 @@
+use {
+    futures::{channel::mpsc, Stream, StreamExt},
+    log::{error, info, warn},
+    std::{
+        fmt,
+        fs::File,
+        io::{self, BufReader},
+        net::IpAddr,
+        path::Path,
+        sync::{Arc, RwLock},
+        time::Duration,
+    },
+    tokio::{net::{TcpListener, TcpStream}, time::timeout},
+    tokio_rustls::{
+        rustls::{
+            internal::pemfile,
+            sign::{self, CertifiedKey},
+            ClientHello, NoClientAuth, ResolvesServerCert, ServerConfig,
+        },
+        server::TlsStream,
+        TlsAcceptor,
+    },
+};
+
+use crate::{config::TlsConfig, service::RemoteAddr, watch::watch_paths};
+
+const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+// Hands out whatever certificate we most recently loaded, so that renewed certificates get
+// picked up without having to restart the server.
+struct ReloadingResolver {
+    key: RwLock<CertifiedKey>,
+}
+
+impl ResolvesServerCert for ReloadingResolver {
+    fn resolve(&self, _: ClientHello) -> Option<CertifiedKey> {
+        Some(self.key.read().expect("poisoned").clone())
+    }
+}
+
+pub fn acceptor(config: &TlsConfig) -> Result<TlsAcceptor, TlsError> {
+    let resolver = Arc::new(ReloadingResolver {
+        key: RwLock::new(load_certified_key(&config.cert, &config.key)?)
+    });
+    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    server_config.cert_resolver = resolver.clone();
+    // Websockets can't be upgraded from HTTP/2 connections:
+    server_config.set_protocols(&[b"http/1.1".to_vec()]);
+
+    let config = config.clone();
+    let mut changes = watch_paths(vec![config.cert.clone(), config.key.clone()], RELOAD_POLL_INTERVAL);
+    tokio::task::spawn(async move {
+        while let Some(()) = changes.next().await {
+            match load_certified_key(&config.cert, &config.key) {
+                Ok(key) => {
+                    info!("Reloaded TLS certificate from {}", config.cert.display());
+                    *resolver.key.write().expect("poisoned") = key;
+                },
+                // We might have caught the files mid-update, so keep going with what we had:
+                Err(e) => warn!("Keeping old TLS certificate: {}", e),
+            }
+        }
+    });
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+// Accepts TCP connections and performs TLS handshakes on them concurrently, so that one slow
+// client can't hold up everybody else. Clients that take longer than handshake_timeout get
+// dropped, so that half-open connections can't pile up. Failed handshakes get logged rather than
+// passed on, as hyper gives up serving entirely if its incoming stream returns an error.
+pub fn incoming(listener: TcpListener, acceptor: TlsAcceptor, handshake_timeout: Duration)
+    -> impl Stream<Item = Result<TlsStream<TcpStream>, io::Error>>
+{
+    let (tx, rx) = mpsc::unbounded();
+    tokio::task::spawn(async move {
+        while !tx.is_closed() {
+            match listener.accept().await {
+                Ok((tcp, addr)) => {
+                    let acceptor = acceptor.clone();
+                    let tx = tx.clone();
+                    tokio::task::spawn(async move {
+                        match timeout(handshake_timeout, acceptor.accept(tcp)).await {
+                            Ok(Ok(stream)) => { let _ = tx.unbounded_send(Ok(stream)); },
+                            Ok(Err(e)) => warn!("TLS handshake with {} failed: {}", addr, e),
+                            Err(_) => warn!("TLS handshake with {} timed out", addr),
+                        }
+                    });
+                },
+                Err(e) => error!("Failed to accept connection: {}", e),
+            }
+        }
+    });
+    rx
+}
+
+impl RemoteAddr for TlsStream<TcpStream> {
+    fn remote_addr(&self) -> Option<IpAddr> {
+        self.get_ref().0.peer_addr().ok().map(|addr| addr.ip())
+    }
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, TlsError> {
+    let certs = pemfile::certs(&mut open(cert_path)?)
+        .map_err(|()| TlsError::new(cert_path, "bad certificate PEM"))?;
+    if certs.is_empty() {
+        return Err(TlsError::new(cert_path, "no certificates found"));
+    }
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(key_path)?)
+        .map_err(|()| TlsError::new(key_path, "bad private key PEM"))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(key_path)?)
+            .map_err(|()| TlsError::new(key_path, "bad private key PEM"))?;
+    }
+    let key = keys.first().ok_or_else(|| TlsError::new(key_path, "no private keys found"))?;
+    let signing_key = sign::any_supported_type(key)
+        .map_err(|()| TlsError::new(key_path, "unsupported private key type"))?;
+    let certified_key = CertifiedKey::new(certs, Arc::new(signing_key));
+    certified_key.cross_check_end_entity_cert(None)
+        .map_err(|e| TlsError::new(cert_path, &format!("doesn't match key: {}", e)))?;
+    Ok(certified_key)
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, TlsError> {
+    File::open(path).map(BufReader::new).map_err(|e| TlsError::new(path, &e.to_string()))
+}
+
+#[derive(Debug)]
+pub struct TlsError(String);
+
+impl TlsError {
+    fn new(path: &Path, msg: &str) -> Self {
+        TlsError(format!("{}: {}", path.display(), msg))
+    }
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/localhost.cert.pem");
+    const KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/localhost.key.pem");
+
+    #[test]
+    fn test_load_self_signed() {
+        let key = load_certified_key(Path::new(CERT), Path::new(KEY)).unwrap();
+        assert_eq!(key.cert.len(), 1);
+    }
+
+    #[test]
+    fn test_load_errors() {
+        assert!(load_certified_key(Path::new(KEY), Path::new(KEY)).is_err());
+        assert!(load_certified_key(Path::new(CERT), Path::new(CERT)).is_err());
+        assert!(load_certified_key(Path::new("/no/such.pem"), Path::new(KEY)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let config = TlsConfig { cert: CERT.into(), key: KEY.into(), handshake_timeout: Duration::from_millis(100) };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _incoming = incoming(listener, acceptor(&config).unwrap(), config.handshake_timeout);
+
+        // A client that connects but never says hello gets hung up on:
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let closed = async {
+            loop {
+                tcp.readable().await.unwrap();
+                match tcp.try_read(&mut [0; 16]) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                    Ok(0) | Err(_) => return,
+                    Ok(_) => panic!("expected nothing from the server"),
+                }
+            }
+        };
+        timeout(Duration::from_secs(5), closed).await.expect("connection left open");
+    }
+}
@@ -0,0 +1,36 @@
+use {
+    futures::{channel::mpsc, SinkExt},
+    log::warn,
+    std::{
+        fs,
+        path::PathBuf,
+        time::{Duration, SystemTime},
+    },
+};
+
+// Polls the modification times of the given paths, sending a notification whenever any of them
+// changes. Polling is cheap for the handful of files we care about, and unlike OS file
+// notifications, copes with files being replaced via symlink swaps or atomic renames.
+pub fn watch_paths(paths: Vec<PathBuf>, interval: Duration) -> mpsc::Receiver<()> {
+    let (mut tx, rx) = mpsc::channel(1);
+    tokio::task::spawn(async move {
+        let mut last = mtimes(&paths);
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            let current = mtimes(&paths);
+            if current != last {
+                for (path, _) in paths.iter().zip(&current).filter(|(_, t)| t.is_none()) {
+                    warn!("Can't watch {}, it has gone missing", path.display());
+                }
+                last = current;
+                if tx.send(()).await.is_err() { break }
+            }
+        }
+    });
+    rx
+}
+
+fn mtimes(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths.iter().map(|p| fs::metadata(p).and_then(|m| m.modified()).ok()).collect()
+}